@@ -0,0 +1,24 @@
+#![no_main]
+
+use erofs_sys::superblock::FileSystem;
+use erofs_sys_fuzz::*;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let Some(fs) = open(data) else {
+        return;
+    };
+    let filesystem: &dyn FileSystem<FuzzInode> = &fs;
+    walk(filesystem, |inode| {
+        if let Ok(iter) = filesystem.read_dir(inode) {
+            for entry in iter.verify_file_types(true).take(MAX_INODES).flatten() {
+                let _ = filesystem.find_nid(inode, entry.name());
+            }
+        }
+        let mut count = 0;
+        let _ = filesystem.fill_dentries(inode, 0, 0, &mut |_, _| {
+            count += 1;
+            count > MAX_INODES
+        });
+    });
+});
@@ -0,0 +1,22 @@
+#![no_main]
+
+use erofs_sys::inode::Inode;
+use erofs_sys::superblock::FileSystem;
+use erofs_sys::Nid;
+use erofs_sys_fuzz::*;
+use libfuzzer_sys::fuzz_target;
+
+// The first 8 bytes select the nid, the rest is the image.
+fuzz_target!(|data: &[u8]| {
+    let Some((nid, image)) = data.split_first_chunk::<8>() else {
+        return;
+    };
+    let Some(fs) = open(image) else {
+        return;
+    };
+    let filesystem: &dyn FileSystem<FuzzInode> = &fs;
+    if let Ok(inode) = read_inode(filesystem, Nid::from_le_bytes(*nid)) {
+        let info = inode.info();
+        let _ = (info.file_size(), info.inode_type());
+    }
+});
@@ -0,0 +1,24 @@
+#![no_main]
+
+use erofs_sys::inode::Inode;
+use erofs_sys::superblock::FileSystem;
+use erofs_sys_fuzz::*;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let Some(fs) = open(data) else {
+        return;
+    };
+    let filesystem: &dyn FileSystem<FuzzInode> = &fs;
+    walk(filesystem, |inode| {
+        let size = inode.info().file_size();
+        for offset in [0, size / 2, size.saturating_sub(1), size] {
+            let _ = filesystem.map(inode, offset);
+        }
+        if let Ok(iter) = filesystem.mapped_iter(inode, 0) {
+            iter.take(8).for_each(drop);
+        }
+        let mut buffer = [0u8; 8192];
+        let _ = filesystem.read_at(inode, size / 3, &mut buffer);
+    });
+});
@@ -0,0 +1,17 @@
+#![no_main]
+
+use erofs_sys::superblock::FileSystem;
+use erofs_sys::Nid;
+use erofs_sys_fuzz::*;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let Some(fs) = open(data) else {
+        return;
+    };
+    let filesystem: &dyn FileSystem<FuzzInode> = &fs;
+    let sb = filesystem.superblock();
+    let _ = sb.dirblksz();
+    let _ = sb.packed_nid();
+    let _ = read_inode(filesystem, sb.root_nid as Nid);
+});
@@ -0,0 +1,24 @@
+#![no_main]
+
+use erofs_sys::superblock::FileSystem;
+use erofs_sys_fuzz::*;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let Some(fs) = open(data) else {
+        return;
+    };
+    let filesystem: &dyn FileSystem<FuzzInode> = &fs;
+    walk(filesystem, |inode| {
+        let mut buffer = [0u8; 512];
+        let _ = filesystem.list_xattrs(inode, &mut []);
+        let _ = filesystem.list_xattrs(inode, &mut buffer);
+        for index in 0..8 {
+            let _ = filesystem.get_xattr(inode, index, b"", &mut None);
+            let _ = filesystem.get_xattr(inode, index, b"sha512sum", &mut Some(&mut buffer));
+        }
+        if let Ok(iter) = filesystem.xattrs(inode) {
+            iter.take(MAX_INODES).for_each(drop);
+        }
+    });
+});
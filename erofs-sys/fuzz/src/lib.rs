@@ -0,0 +1,74 @@
+// Copyright 2024 Yiyang Wu
+// SPDX-License-Identifier: MIT or GPL-2.0-or-later
+
+//! Shared plumbing for the erofs-sys fuzz targets: an in-memory image source, a minimal inode and
+//! a bounded walk over the inodes reachable from the root directory.
+
+use erofs_sys::data::backends::uncompressed::UncompressedBackend;
+use erofs_sys::file::ImageFileSystem;
+use erofs_sys::inode::*;
+use erofs_sys::superblock::{FileSystem, SuperBlock};
+use erofs_sys::xattrs::*;
+use erofs_sys::{Nid, PosixResult};
+
+/// Upper bound of inodes visited per input so that a single input cannot stall the fuzzer.
+pub const MAX_INODES: usize = 64;
+
+/// The filesystem type exercised by every target, the image is the fuzzer input itself.
+pub type FuzzFileSystem<'a> = ImageFileSystem<UncompressedBackend<&'a [u8]>>;
+
+pub struct FuzzInode {
+    info: InodeInfo,
+    xattr_shared_entries: XAttrSharedEntries,
+    nid: Nid,
+}
+
+impl Inode for FuzzInode {
+    fn new(_sb: &SuperBlock, info: InodeInfo, nid: Nid, xattr_header: XAttrSharedEntries) -> Self {
+        Self {
+            info,
+            xattr_shared_entries: xattr_header,
+            nid,
+        }
+    }
+    fn xattrs_shared_entries(&self) -> &XAttrSharedEntries {
+        &self.xattr_shared_entries
+    }
+    fn nid(&self) -> Nid {
+        self.nid
+    }
+    fn info(&self) -> &InodeInfo {
+        &self.info
+    }
+}
+
+/// Mount the input, returning None if the superblock is rejected.
+pub fn open(data: &[u8]) -> Option<FuzzFileSystem<'_>> {
+    ImageFileSystem::try_new(UncompressedBackend::new(data)).ok()
+}
+
+/// Read a single inode through the same steps an InodeCollection would take.
+pub fn read_inode(fs: &dyn FileSystem<FuzzInode>, nid: Nid) -> PosixResult<FuzzInode> {
+    let info = fs.read_inode_info(nid)?;
+    let shared = fs.read_inode_xattrs_shared_entries(nid, &info)?;
+    Ok(FuzzInode::new(fs.superblock(), info, nid, shared))
+}
+
+/// Visit up to MAX_INODES inodes reachable from the root directory.
+pub fn walk(fs: &dyn FileSystem<FuzzInode>, mut f: impl FnMut(&FuzzInode)) {
+    let mut pending = vec![fs.superblock().root_nid as Nid];
+    let mut visited = Vec::new();
+    while let Some(nid) = pending.pop() {
+        if visited.contains(&nid) || visited.len() >= MAX_INODES {
+            continue;
+        }
+        visited.push(nid);
+        let Ok(inode) = read_inode(fs, nid) else {
+            continue;
+        };
+        f(&inode);
+        if let Ok(iter) = fs.read_dir(&inode) {
+            pending.extend(iter.take(MAX_INODES).flatten().map(|entry| entry.nid()));
+        };
+    }
+}
@@ -62,3 +62,31 @@ pub(crate) fn vec_with_capacity<T: Default + Clone>(capacity: usize) -> PosixRes
         () => Ok(vec![Default::default(); capacity]),
     }
 }
+
+pub(crate) fn resize_vec<T: Clone>(v: &mut Vec<T>, len: usize, value: T) -> PosixResult<()> {
+    match () {
+        #[cfg(CONFIG_EROFS_FS = "y")]
+        () => v
+            .resize(len, value, GFP_KERNEL)
+            .map_or_else(|_| Err(ENOMEM), |_| Ok(())),
+        #[cfg(not(CONFIG_EROFS_FS = "y"))]
+        () => {
+            v.resize(len, value);
+            Ok(())
+        }
+    }
+}
+
+pub(crate) fn reserve_vec<T>(v: &mut Vec<T>, additional: usize) -> PosixResult<()> {
+    match () {
+        #[cfg(CONFIG_EROFS_FS = "y")]
+        () => v
+            .reserve(additional, GFP_KERNEL)
+            .map_or_else(|_| Err(ENOMEM), |_| Ok(())),
+        #[cfg(not(CONFIG_EROFS_FS = "y"))]
+        () => {
+            v.reserve(additional);
+            Ok(())
+        }
+    }
+}
@@ -0,0 +1,315 @@
+// Copyright 2024 Yiyang Wu
+// SPDX-License-Identifier: MIT or GPL-2.0-or-later
+
+use alloc::vec::Vec;
+use core::cell::UnsafeCell;
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use super::alloc_helper::*;
+use super::*;
+
+/// A lock that never waits. Caches in this crate are only an optimization, so whenever the lock
+/// is contended the caller simply bypasses the cache and goes to the backend instead.
+/// This keeps the filesystem Sync without depending on any std or kernel lock primitives.
+pub(crate) struct TryLock<T> {
+    locked: AtomicBool,
+    data: UnsafeCell<T>,
+}
+
+// SAFETY: The data can only be reached through a TryLockGuard and at most one guard can exist at
+// a time, so sharing the lock between threads is as safe as sending the data itself.
+unsafe impl<T: Send> Sync for TryLock<T> {}
+
+pub(crate) struct TryLockGuard<'a, T> {
+    lock: &'a TryLock<T>,
+}
+
+impl<T> TryLock<T> {
+    pub(crate) const fn new(data: T) -> Self {
+        Self {
+            locked: AtomicBool::new(false),
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    pub(crate) fn try_lock(&self) -> Option<TryLockGuard<'_, T>> {
+        self.locked
+            .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .ok()
+            .map(|_| TryLockGuard { lock: self })
+    }
+}
+
+impl<'a, T> Deref for TryLockGuard<'a, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        // SAFETY: The guard is the unique owner of the lock.
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<'a, T> DerefMut for TryLockGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        // SAFETY: The guard is the unique owner of the lock.
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+
+impl<'a, T> Drop for TryLockGuard<'a, T> {
+    fn drop(&mut self) {
+        self.lock.locked.store(false, Ordering::Release);
+    }
+}
+
+struct LruEntry<K, V> {
+    key: K,
+    value: V,
+    stamp: u64,
+}
+
+/// A small least-recently-used cache bounded by the number of entries.
+/// Lookups are linear so this is only meant for caches with a handful of hot entries.
+pub(crate) struct LruCache<K, V> {
+    entries: Vec<LruEntry<K, V>>,
+    capacity: usize,
+    clock: u64,
+}
+
+impl<K, V> LruCache<K, V>
+where
+    K: PartialEq + Copy,
+{
+    pub(crate) const fn new(capacity: usize) -> Self {
+        Self {
+            entries: Vec::new(),
+            capacity,
+            clock: 0,
+        }
+    }
+
+    pub(crate) fn get(&mut self, key: K) -> Option<&V> {
+        self.clock += 1;
+        let clock = self.clock;
+        self.entries.iter_mut().find(|e| e.key == key).map(|e| {
+            e.stamp = clock;
+            &e.value
+        })
+    }
+
+    pub(crate) fn insert(&mut self, key: K, value: V) -> PosixResult<()> {
+        if self.capacity == 0 {
+            return Ok(());
+        }
+        self.clock += 1;
+        let entry = LruEntry {
+            key,
+            value,
+            stamp: self.clock,
+        };
+        if let Some(slot) = self.entries.iter_mut().find(|e| e.key == key) {
+            *slot = entry;
+        } else if self.entries.len() < self.capacity {
+            push_vec(&mut self.entries, entry)?;
+        } else if let Some(slot) = self.entries.iter_mut().min_by_key(|e| e.stamp) {
+            *slot = entry;
+        }
+        Ok(())
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.entries.len()
+    }
+}
+
+const NIL: usize = usize::MAX;
+
+struct BlockSlot<K> {
+    key: K,
+    data: Vec<u8>,
+    prev: usize,
+    next: usize,
+}
+
+/// A least-recently-used cache of data blocks for caches too large for [`LruCache`]. Keys are
+/// kept sorted for logarithmic lookups and the slots form a list from the most to the least
+/// recently used, so that eviction is constant time.
+pub(crate) struct BlockLru<K> {
+    index: Vec<(K, usize)>,
+    slots: Vec<BlockSlot<K>>,
+    capacity: usize,
+    head: usize,
+    tail: usize,
+}
+
+impl<K> BlockLru<K>
+where
+    K: Ord + Copy,
+{
+    pub(crate) const fn new(capacity: usize) -> Self {
+        Self {
+            index: Vec::new(),
+            slots: Vec::new(),
+            capacity,
+            head: NIL,
+            tail: NIL,
+        }
+    }
+
+    fn unlink(&mut self, slot: usize) {
+        let (prev, next) = (self.slots[slot].prev, self.slots[slot].next);
+        match prev {
+            NIL => self.head = next,
+            prev => self.slots[prev].next = next,
+        }
+        match next {
+            NIL => self.tail = prev,
+            next => self.slots[next].prev = prev,
+        }
+    }
+
+    fn push_front(&mut self, slot: usize) {
+        self.slots[slot].prev = NIL;
+        self.slots[slot].next = self.head;
+        match self.head {
+            NIL => self.tail = slot,
+            head => self.slots[head].prev = slot,
+        }
+        self.head = slot;
+    }
+
+    fn touch(&mut self, slot: usize) {
+        if self.head != slot {
+            self.unlink(slot);
+            self.push_front(slot);
+        }
+    }
+
+    pub(crate) fn get(&mut self, key: K) -> Option<&[u8]> {
+        let pos = self.index.binary_search_by_key(&key, |e| e.0).ok()?;
+        let slot = self.index[pos].1;
+        self.touch(slot);
+        Some(&self.slots[slot].data)
+    }
+
+    pub(crate) fn contains(&self, key: K) -> bool {
+        self.index.binary_search_by_key(&key, |e| e.0).is_ok()
+    }
+
+    pub(crate) fn insert(&mut self, key: K, data: Vec<u8>) -> PosixResult<()> {
+        if self.capacity == 0 {
+            return Ok(());
+        }
+        if let Ok(pos) = self.index.binary_search_by_key(&key, |e| e.0) {
+            let slot = self.index[pos].1;
+            self.slots[slot].data = data;
+            self.touch(slot);
+            return Ok(());
+        }
+        // Reserved up front so that failing to grow the index can't leak an evicted slot.
+        reserve_vec(&mut self.index, 1)?;
+        let slot = if self.slots.len() < self.capacity {
+            push_vec(
+                &mut self.slots,
+                BlockSlot {
+                    key,
+                    data,
+                    prev: NIL,
+                    next: NIL,
+                },
+            )?;
+            self.slots.len() - 1
+        } else {
+            let slot = self.tail;
+            let old = self.slots[slot].key;
+            if let Ok(old) = self.index.binary_search_by_key(&old, |e| e.0) {
+                self.index.remove(old);
+            }
+            self.unlink(slot);
+            self.slots[slot].key = key;
+            self.slots[slot].data = data;
+            slot
+        };
+        // Searched again since removing the evicted key may have shifted the insertion point.
+        let pos = self
+            .index
+            .binary_search_by_key(&key, |e| e.0)
+            .unwrap_or_else(|pos| pos);
+        push_vec(&mut self.index, (key, slot))?;
+        self.index[pos..].rotate_right(1);
+        self.push_front(slot);
+        Ok(())
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.slots.len()
+    }
+
+    pub(crate) fn capacity(&self) -> usize {
+        self.capacity
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lru_eviction() {
+        let mut cache: LruCache<u32, u32> = LruCache::new(2);
+        cache.insert(1, 10).unwrap();
+        cache.insert(2, 20).unwrap();
+        assert_eq!(cache.get(1), Some(&10));
+        cache.insert(3, 30).unwrap();
+        assert_eq!(cache.len(), 2);
+        assert_eq!(cache.get(2), None);
+        assert_eq!(cache.get(1), Some(&10));
+        assert_eq!(cache.get(3), Some(&30));
+    }
+
+    #[test]
+    fn test_block_lru_eviction() {
+        extern crate std;
+        use std::vec;
+
+        let mut cache: BlockLru<u32> = BlockLru::new(3);
+        for key in [5, 1, 9] {
+            cache.insert(key, vec![key as u8]).unwrap();
+        }
+        assert_eq!(cache.get(5), Some(&[5u8][..]));
+        // 1 is now the least recently used one.
+        cache.insert(3, vec![3]).unwrap();
+        assert_eq!(cache.len(), 3);
+        assert!(!cache.contains(1));
+        cache.insert(9, vec![90]).unwrap();
+        cache.insert(7, vec![7]).unwrap();
+        assert!(!cache.contains(5));
+        assert_eq!(cache.get(3), Some(&[3u8][..]));
+        assert_eq!(cache.get(9), Some(&[90u8][..]));
+        assert_eq!(cache.get(7), Some(&[7u8][..]));
+        assert_eq!(
+            cache.index.iter().map(|e| e.0).collect::<Vec<_>>(),
+            [3, 7, 9]
+        );
+
+        // Many more keys than slots, in an order that keeps shuffling the index.
+        let mut cache: BlockLru<u32> = BlockLru::new(16);
+        for i in 0..1000u32 {
+            let key = i.wrapping_mul(2_654_435_761) % 257;
+            cache.insert(key, vec![key as u8]).unwrap();
+            assert_eq!(cache.get(key), Some(&[key as u8][..]));
+            assert!(cache.index.windows(2).all(|w| w[0].0 < w[1].0));
+        }
+        assert_eq!(cache.len(), 16);
+    }
+
+    #[test]
+    fn test_try_lock_contention() {
+        let lock = TryLock::new(0);
+        let mut guard = lock.try_lock().unwrap();
+        *guard += 1;
+        assert!(lock.try_lock().is_none());
+        drop(guard);
+        assert_eq!(*lock.try_lock().unwrap(), 1);
+    }
+}
@@ -3,7 +3,15 @@
 
 /// Backend modules
 pub mod backends;
+/// Locate images embedded in disk images and other files.
+pub mod partitions;
+pub(crate) mod pool;
 pub(crate) mod raw_iters;
+/// Built-in sources for images in memory, in files and in memory mappings. They all ignore the
+/// device id, so only single-device images are supported.
+pub mod sources;
+/// Android sparse image source.
+pub mod sparse;
 
 use alloc::boxed::Box;
 use alloc::vec::Vec;
@@ -122,3 +130,41 @@ impl<'a> Drop for RefBuffer<'a> {
         (self.put_buf)(self.buf.as_ptr() as *mut core::ffi::c_void)
     }
 }
+
+/// Represents a block yielded by the buffer iterators. It either borrows the data of a memory
+/// backend or owns a temporary copy, without another heap allocation to hold it.
+pub struct BlockBuffer<'a>(BlockBufferInner<'a>);
+
+enum BlockBufferInner<'a> {
+    Ref(RefBuffer<'a>),
+    Temp(TempBuffer),
+    Pooled(pool::PooledBuffer<'a>),
+}
+
+impl<'a> Buffer for BlockBuffer<'a> {
+    fn content(&self) -> &[u8] {
+        match &self.0 {
+            BlockBufferInner::Ref(buf) => buf.content(),
+            BlockBufferInner::Temp(buf) => buf.content(),
+            BlockBufferInner::Pooled(buf) => buf.content(),
+        }
+    }
+}
+
+impl<'a> From<RefBuffer<'a>> for BlockBuffer<'a> {
+    fn from(value: RefBuffer<'a>) -> Self {
+        Self(BlockBufferInner::Ref(value))
+    }
+}
+
+impl<'a> From<TempBuffer> for BlockBuffer<'a> {
+    fn from(value: TempBuffer) -> Self {
+        Self(BlockBufferInner::Temp(value))
+    }
+}
+
+impl<'a> From<pool::PooledBuffer<'a>> for BlockBuffer<'a> {
+    fn from(value: pool::PooledBuffer<'a>) -> Self {
+        Self(BlockBufferInner::Pooled(value))
+    }
+}
@@ -1,5 +1,7 @@
 // Copyright 2024 Yiyang Wu
 // SPDX-License-Identifier: MIT or GPL-2.0-or-later
 
+/// caching backends.
+pub mod cached;
 /// uncompressed backends.
 pub mod uncompressed;
@@ -0,0 +1,339 @@
+// Copyright 2024 Yiyang Wu
+// SPDX-License-Identifier: MIT or GPL-2.0-or-later
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use super::super::super::alloc_helper::*;
+use super::super::super::cache::*;
+use super::super::super::errnos::*;
+use super::super::super::file::EROFS_MAX_IO_LEN;
+use super::super::*;
+
+/// Hit and miss counters of a [`CachedBackend`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    hits: usize,
+    misses: usize,
+    bypassed: usize,
+}
+
+impl CacheStats {
+    /// Blocks served from the cache.
+    pub fn hits(&self) -> usize {
+        self.hits
+    }
+    /// Blocks which had to be read from the backend, readahead excluded.
+    pub fn misses(&self) -> usize {
+        self.misses
+    }
+    /// Reads which went straight to the backend because another thread held the cache.
+    pub fn bypassed(&self) -> usize {
+        self.bypassed
+    }
+}
+
+/// Keeps the most recently used blocks of a backend in memory, so that metadata read over and
+/// over again such as inode tables, directory blocks and shared xattrs only costs one read.
+/// Blocks are keyed by device and offset, so any read pattern can be served from the cache.
+pub struct CachedBackend<B>
+where
+    B: Backend,
+{
+    backend: B,
+    cache: TryLock<BlockLru<(i32, Off)>>,
+    blkszbits: u8,
+    readahead: usize,
+    hits: AtomicUsize,
+    misses: AtomicUsize,
+    bypassed: AtomicUsize,
+}
+
+impl<B> CachedBackend<B>
+where
+    B: Backend,
+{
+    /// Cache blocks of `block_size` bytes of `backend` within a memory budget of `budget` bytes.
+    /// The block size must be a power of two of at least 512 bytes, it doesn't need to match the
+    /// block size of the image.
+    pub fn new(backend: B, block_size: usize, budget: usize) -> PosixResult<Self> {
+        if !block_size.is_power_of_two() || block_size < 512 {
+            return Err(EINVAL);
+        }
+        Ok(Self {
+            backend,
+            cache: TryLock::new(BlockLru::new(budget / block_size)),
+            blkszbits: block_size.trailing_zeros() as u8,
+            readahead: 0,
+            hits: AtomicUsize::new(0),
+            misses: AtomicUsize::new(0),
+            bypassed: AtomicUsize::new(0),
+        })
+    }
+
+    /// Read this many blocks past a missed block in the same backend call. Worth it for images
+    /// on slow storage where a few large reads beat many small ones.
+    pub fn readahead_blocks(mut self, blocks: usize) -> Self {
+        self.readahead = blocks;
+        self
+    }
+
+    /// A snapshot of the counters.
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            bypassed: self.bypassed.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Give back the underlying backend.
+    pub fn into_inner(self) -> B {
+        self.backend
+    }
+
+    fn block_size(&self) -> Off {
+        1 << self.blkszbits
+    }
+
+    /// The most blocks worth reading in one go: more than the cache holds would only push out
+    /// what was just read, and reads are kept within EROFS_MAX_IO_LEN.
+    fn max_batch(&self, cache: &BlockLru<(i32, Off)>) -> usize {
+        cache
+            .capacity()
+            .min((EROFS_MAX_IO_LEN >> self.blkszbits) as usize)
+            .max(1)
+    }
+
+    /// Read up to `count` blocks starting at `blk` with one backend call and cache them. Stops at
+    /// the first short block, which is cached as well since it marks the end of the device. The
+    /// first block is inserted last so that readahead never pushes it out of a small cache.
+    fn load(
+        &self,
+        cache: &mut BlockLru<(i32, Off)>,
+        device_id: i32,
+        blk: Off,
+        count: usize,
+    ) -> PosixResult<()> {
+        let bs = self.block_size() as usize;
+        let count = count.clamp(1, self.max_batch(cache));
+        let offset = blk.checked_mul(bs as Off).ok_or(EINVAL)?;
+        let size = count.checked_mul(bs).ok_or(EINVAL)?;
+        let mut buf = vec_with_capacity(size)?;
+        resize_vec(&mut buf, size, 0)?;
+        let len = self.backend.fill(&mut buf, device_id, offset)? as usize;
+        let blocks = count.min(len / bs + 1);
+        for i in (1..blocks).chain(0..1) {
+            let start = i * bs;
+            let end = len.clamp(start, start + bs);
+            let mut block = Vec::new();
+            extend_from_slice(&mut block, &buf[start..end])?;
+            cache.insert((device_id, blk + i as Off), block)?;
+        }
+        Ok(())
+    }
+
+    /// Hint that the given range is going to be read soon and pull whatever of it isn't cached
+    /// yet into the cache, batching neighbouring blocks into single reads. Only the start of the
+    /// range which fits in the cache is read. Does nothing if the cache is busy.
+    pub fn readahead(&self, device_id: i32, offset: Off, len: Off) -> PosixResult<()> {
+        let Some(mut cache) = self.cache.try_lock() else {
+            return Ok(());
+        };
+        if len == 0 || cache.capacity() == 0 {
+            return Ok(());
+        }
+        let first = offset >> self.blkszbits;
+        let last = (offset.saturating_add(len - 1) >> self.blkszbits)
+            .min(first.saturating_add(cache.capacity() as Off - 1));
+        let batch = self.max_batch(&cache) as Off;
+        let mut blk = first;
+        while blk <= last {
+            if cache.contains((device_id, blk)) {
+                blk += 1;
+                continue;
+            }
+            let mut count = 1;
+            while count < batch && count <= last - blk && !cache.contains((device_id, blk + count))
+            {
+                count += 1;
+            }
+            self.load(&mut cache, device_id, blk, count as usize)?;
+            blk = match blk.checked_add(count) {
+                Some(blk) => blk,
+                None => break,
+            };
+        }
+        Ok(())
+    }
+}
+
+impl<B> Backend for CachedBackend<B>
+where
+    B: Backend,
+{
+    fn fill(&self, data: &mut [u8], device_id: i32, offset: Off) -> PosixResult<u64> {
+        let Some(mut cache) = self.cache.try_lock() else {
+            self.bypassed.fetch_add(1, Ordering::Relaxed);
+            return self.backend.fill(data, device_id, offset);
+        };
+        if cache.capacity() == 0 {
+            return self.backend.fill(data, device_id, offset);
+        }
+        let bs = self.block_size() as usize;
+        let mut done = 0;
+        while done < data.len() {
+            let Some(pos) = offset.checked_add(done as Off) else {
+                break;
+            };
+            let blk = pos >> self.blkszbits;
+            let skip = (pos & (self.block_size() - 1)) as usize;
+            if cache.contains((device_id, blk)) {
+                self.hits.fetch_add(1, Ordering::Relaxed);
+            } else {
+                self.misses.fetch_add(1, Ordering::Relaxed);
+                self.load(&mut cache, device_id, blk, self.readahead.saturating_add(1))?;
+            }
+            let block = cache.get((device_id, blk)).ok_or(EIO)?;
+            let rest = block.get(skip..).unwrap_or_default();
+            let len = rest.len().min(data.len() - done);
+            data[done..done + len].copy_from_slice(&rest[..len]);
+            done += len;
+            // A short block is the end of the device.
+            if block.len() < bs {
+                break;
+            }
+        }
+        Ok(done as u64)
+    }
+}
+
+impl<B> FileBackend for CachedBackend<B> where B: FileBackend {}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::super::super::super::file::ImageFileSystem;
+    use super::super::super::super::superblock::tests::*;
+    use super::super::uncompressed::*;
+    use super::*;
+    use alloc::boxed::Box;
+    use std::collections::HashMap;
+    use std::io::Read;
+    use std::sync::Arc;
+    use std::vec;
+
+    fn counting_backend(image: Vec<u8>) -> (UncompressedBackend<CountingSource>, Arc<AtomicUsize>) {
+        let source = CountingSource::new(image);
+        let reads = source.reads.clone();
+        (UncompressedBackend::new(source), reads)
+    }
+
+    #[test]
+    fn test_cached_backend_filesystem() {
+        for mut testcase in load_fixtures_full().chain(load_fixtures_noxattr()) {
+            let mut image = Vec::new();
+            testcase.file.read_to_end(&mut image).unwrap();
+            // Plenty of room, a single block and no room at all must all behave the same.
+            for budget in [1 << 20, 4096, 0] {
+                let (backend, _) = counting_backend(image.clone());
+                let backend = CachedBackend::new(backend, 4096, budget)
+                    .unwrap()
+                    .readahead_blocks(2);
+                let mut sbi: SimpleBufferedFileSystem = SuperblockInfo::new(
+                    Box::new(ImageFileSystem::try_new(backend).unwrap()),
+                    HashMap::new(),
+                    (),
+                );
+                test_filesystem(&mut sbi, testcase.xattrs);
+            }
+        }
+    }
+
+    #[test]
+    fn test_cached_backend_reads() {
+        let image = (0..5 * 4096 + 100)
+            .map(|i| (i % 251) as u8)
+            .collect::<Vec<_>>();
+        let (backend, reads) = counting_backend(image.clone());
+        let backend = CachedBackend::new(backend, 4096, 1 << 20).unwrap();
+        assert!(CachedBackend::new(UncompressedBackend::new(vec![0u8]), 1000, 0).is_err());
+
+        // Sub-block reads of the same block only go to the backend once.
+        let mut data = [0u8; 100];
+        for offset in [10, 200, 4000] {
+            assert_eq!(backend.fill(&mut data[..50], 0, offset), Ok(50));
+            assert_eq!(data[..50], image[offset as usize..offset as usize + 50]);
+        }
+        assert_eq!(reads.load(Ordering::Relaxed), 1);
+        assert_eq!(backend.stats().hits(), 2);
+        assert_eq!(backend.stats().misses(), 1);
+
+        // A read across a block boundary, and the short block at the end of the image.
+        assert_eq!(backend.fill(&mut data, 0, 4050), Ok(100));
+        assert_eq!(data[..], image[4050..4150]);
+        assert_eq!(backend.fill(&mut data, 0, 5 * 4096 + 50), Ok(50));
+        assert_eq!(data[..50], image[5 * 4096 + 50..]);
+        assert_eq!(backend.fill(&mut data, 0, 6 * 4096), Ok(0));
+        assert_eq!(backend.fill(&mut data, 0, Off::MAX - 10), Ok(0));
+        let reads_before = reads.load(Ordering::Relaxed);
+
+        // Readahead batches the missing blocks into a single read and makes them hits later.
+        backend.readahead(0, 2 * 4096, 3 * 4096).unwrap();
+        assert_eq!(reads.load(Ordering::Relaxed), reads_before + 1);
+        let hits = backend.stats().hits();
+        let mut all = vec![0u8; image.len()];
+        assert_eq!(backend.fill(&mut all, 0, 0), Ok(image.len() as u64));
+        assert_eq!(all, image);
+        assert_eq!(backend.stats().hits(), hits + 6);
+        assert_eq!(reads.load(Ordering::Relaxed), reads_before + 1);
+        // Different devices never share blocks.
+        assert_eq!(backend.fill(&mut data, 1, 0), Ok(100));
+        assert_eq!(reads.load(Ordering::Relaxed), reads_before + 2);
+    }
+
+    #[test]
+    fn test_cached_backend_bounds() {
+        let image = (0..4 * 4096).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+
+        // Without room every read goes straight to the backend, once.
+        let (backend, reads) = counting_backend(image.clone());
+        let backend = CachedBackend::new(backend, 4096, 0).unwrap();
+        let mut data = [0u8; 100];
+        assert_eq!(backend.fill(&mut data, 0, 4000), Ok(100));
+        assert_eq!(data[..], image[4000..4100]);
+        assert_eq!(reads.load(Ordering::Relaxed), 1);
+        backend.readahead(0, 0, 1 << 40).unwrap();
+        assert_eq!(reads.load(Ordering::Relaxed), 1);
+
+        // Huge ranges and readahead only read what fits in the cache.
+        let (backend, reads) = counting_backend(image.clone());
+        let backend = CachedBackend::new(backend, 4096, 2 * 4096)
+            .unwrap()
+            .readahead_blocks(usize::MAX);
+        backend.readahead(0, Off::MAX - 10, Off::MAX).unwrap();
+        backend.readahead(0, 0, 1 << 40).unwrap();
+        assert_eq!(reads.load(Ordering::Relaxed), 2);
+        assert_eq!(backend.fill(&mut data, 0, 4050), Ok(100));
+        assert_eq!(data[..], image[4050..4150]);
+        assert_eq!(reads.load(Ordering::Relaxed), 2);
+        assert_eq!(backend.fill(&mut data, 0, 3 * 4096), Ok(100));
+        assert_eq!(data[..], image[3 * 4096..3 * 4096 + 100]);
+        assert_eq!(reads.load(Ordering::Relaxed), 3);
+    }
+
+    #[test]
+    fn test_cached_backend_contention() {
+        let (backend, reads) = counting_backend(vec![7u8; 8192]);
+        let backend = CachedBackend::new(backend, 4096, 1 << 20).unwrap();
+        let guard = backend.cache.try_lock().unwrap();
+        let mut data = [0u8; 16];
+        assert_eq!(backend.fill(&mut data, 0, 0), Ok(16));
+        assert_eq!(backend.fill(&mut data, 0, 0), Ok(16));
+        assert_eq!(backend.stats().bypassed(), 2);
+        assert_eq!(reads.load(Ordering::Relaxed), 2);
+        drop(guard);
+        assert_eq!(backend.fill(&mut data, 0, 0), Ok(16));
+        assert_eq!(backend.stats().misses(), 1);
+    }
+}
@@ -0,0 +1,310 @@
+// Copyright 2024 Yiyang Wu
+// SPDX-License-Identifier: MIT or GPL-2.0-or-later
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::ops::Range;
+
+use super::super::alloc_helper::*;
+use super::super::errnos::*;
+use super::super::superblock::*;
+use super::super::*;
+use super::*;
+
+const GPT_SIGNATURE: &[u8; 8] = b"EFI PART";
+const GPT_ENTRY_MIN_SIZE: usize = 128;
+/// Partition tables are normally 16KiB, anything much larger is a corrupted header.
+const GPT_TABLE_MAX_SIZE: usize = 1 << 20;
+const SCAN_CHUNK_SIZE: usize = 1 << 20;
+
+/// Return the byte range of the EROFS image starting at `start`, or None if there is no valid
+/// superblock there. The length is taken from the block count of the superblock.
+pub fn probe(source: &dyn Source, start: Off) -> PosixResult<Option<Range<Off>>> {
+    let Some(offset) = start.checked_add(EROFS_SUPER_OFFSET) else {
+        return Ok(None);
+    };
+    let mut buf = [0u8; 128];
+    if source.fill(&mut buf, 0, offset)? != buf.len() as u64 {
+        return Ok(None);
+    }
+    let sb: SuperBlock = buf.into();
+    if sb.magic != EROFS_SUPER_MAGIC_V1 || sb.validate().is_err() {
+        return Ok(None);
+    }
+    let len = (sb.blocks as u32 as Off) << sb.blkszbits;
+    Ok(start.checked_add(len).map(|end| start..end))
+}
+
+/// Scan `source` for EROFS superblocks at every multiple of `align` bytes and return the byte
+/// ranges of the images found, for images appended to binaries or packed in containers without a
+/// partition table. The magic alone can show up in file data, so every candidate superblock is
+/// validated, but callers should still be prepared for an image failing to open.
+pub fn scan(source: &dyn Source, align: Off) -> PosixResult<Vec<Range<Off>>> {
+    if align < 4 || align as usize > SCAN_CHUNK_SIZE || SCAN_CHUNK_SIZE % align as usize != 0 {
+        return Err(EINVAL);
+    }
+    let magic = EROFS_SUPER_MAGIC_V1.to_le_bytes();
+    let mut found = Vec::new();
+    let mut chunk = vec_with_capacity(SCAN_CHUNK_SIZE)?;
+    resize_vec(&mut chunk, SCAN_CHUNK_SIZE, 0)?;
+    let mut base: Off = 0;
+    loop {
+        // The chunk holds the magic of every image starting in [base, base + SCAN_CHUNK_SIZE).
+        let len = source.fill(&mut chunk, 0, base + EROFS_SUPER_OFFSET)? as usize;
+        for pos in (0..len.saturating_sub(3)).step_by(align as usize) {
+            if chunk[pos..pos + 4] != magic {
+                continue;
+            }
+            let start = base + pos as Off;
+            if let Some(image) = probe(source, start)? {
+                push_vec(&mut found, image)?;
+            }
+        }
+        if len < SCAN_CHUNK_SIZE {
+            return Ok(found);
+        }
+        base += SCAN_CHUNK_SIZE as Off;
+    }
+}
+
+/// An entry of a GUID partition table.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Partition {
+    index: u32,
+    range: Range<Off>,
+    type_guid: [u8; 16],
+    unique_guid: [u8; 16],
+    name: [u16; 36],
+}
+
+impl Partition {
+    /// The position of the entry in the partition table, starting from 0.
+    pub fn index(&self) -> u32 {
+        self.index
+    }
+    /// The byte range of the partition.
+    pub fn range(&self) -> Range<Off> {
+        self.range.clone()
+    }
+    /// The partition type GUID in its on-disk mixed-endian layout.
+    pub fn type_guid(&self) -> &[u8; 16] {
+        &self.type_guid
+    }
+    /// The unique partition GUID in its on-disk mixed-endian layout.
+    pub fn unique_guid(&self) -> &[u8; 16] {
+        &self.unique_guid
+    }
+    /// The partition name, with invalid UTF-16 replaced.
+    pub fn name(&self) -> String {
+        let len = self
+            .name
+            .iter()
+            .position(|c| *c == 0)
+            .unwrap_or(self.name.len());
+        char::decode_utf16(self.name[..len].iter().copied())
+            .map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER))
+            .collect()
+    }
+    /// Whether the partition holds an EROFS image according to its superblock.
+    pub fn is_erofs(&self, source: &dyn Source) -> PosixResult<bool> {
+        Ok(probe(source, self.range.start)?.is_some_and(|image| image.end <= self.range.end))
+    }
+}
+
+fn gpt_partitions_with(source: &dyn Source, sector: Off) -> PosixResult<Option<Vec<Partition>>> {
+    let mut header = [0u8; 92];
+    if source.fill(&mut header, 0, sector)? != header.len() as u64 || &header[0..8] != GPT_SIGNATURE
+    {
+        return Ok(None);
+    }
+    let u32_at = |b: &[u8], pos: usize| u32::from_le_bytes(b[pos..pos + 4].try_into().unwrap());
+    let u64_at = |b: &[u8], pos: usize| u64::from_le_bytes(b[pos..pos + 8].try_into().unwrap());
+    let table = u64_at(&header, 72).checked_mul(sector).ok_or(EUCLEAN)?;
+    let count = u32_at(&header, 80) as usize;
+    let entry_size = u32_at(&header, 84) as usize;
+    if entry_size < GPT_ENTRY_MIN_SIZE || entry_size % 8 != 0 {
+        return Err(EUCLEAN);
+    }
+    let size = count.checked_mul(entry_size).ok_or(EUCLEAN)?;
+    if size > GPT_TABLE_MAX_SIZE {
+        return Err(EUCLEAN);
+    }
+    let mut entries = vec_with_capacity(size)?;
+    resize_vec(&mut entries, size, 0)?;
+    if source.fill(&mut entries, 0, table)? != size as u64 {
+        return Err(EUCLEAN);
+    }
+
+    let mut partitions = Vec::new();
+    for (index, entry) in entries.chunks_exact(entry_size).enumerate() {
+        let type_guid: [u8; 16] = entry[0..16].try_into().unwrap();
+        if type_guid == [0; 16] {
+            continue;
+        }
+        let (first, last) = (u64_at(entry, 32), u64_at(entry, 40));
+        if first > last {
+            return Err(EUCLEAN);
+        }
+        let start = first.checked_mul(sector).ok_or(EUCLEAN)?;
+        let end = last
+            .checked_add(1)
+            .and_then(|l| l.checked_mul(sector))
+            .ok_or(EUCLEAN)?;
+        let mut name = [0u16; 36];
+        for (i, c) in entry[56..128].chunks_exact(2).enumerate() {
+            name[i] = u16::from_le_bytes([c[0], c[1]]);
+        }
+        push_vec(
+            &mut partitions,
+            Partition {
+                index: index as u32,
+                range: start..end,
+                type_guid,
+                unique_guid: entry[16..32].try_into().unwrap(),
+                name,
+            },
+        )?;
+    }
+    Ok(Some(partitions))
+}
+
+/// Read the primary GUID partition table of a disk image with either 512 or 4096 byte sectors and
+/// return its used entries. Checksums are not verified. Fails with ENODEV if there is no table.
+pub fn gpt_partitions(source: &dyn Source) -> PosixResult<Vec<Partition>> {
+    for sector in [512, 4096] {
+        if let Some(partitions) = gpt_partitions_with(source, sector)? {
+            return Ok(partitions);
+        }
+    }
+    Err(ENODEV)
+}
+
+/// The partitions of a GPT disk image that hold an EROFS image, whatever their type GUID says.
+pub fn gpt_erofs_partitions(source: &dyn Source) -> PosixResult<Vec<Partition>> {
+    let mut found = Vec::new();
+    for partition in gpt_partitions(source)? {
+        if partition.is_erofs(source)? {
+            push_vec(&mut found, partition)?;
+        }
+    }
+    Ok(found)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::super::file::ImageFileSystem;
+    use super::super::super::superblock::tests::*;
+    use super::super::backends::uncompressed::*;
+    use super::super::sources::*;
+    use super::*;
+    use alloc::boxed::Box;
+    use std::collections::HashMap;
+    use std::io::Read;
+    use std::vec;
+
+    fn fixture() -> Vec<u8> {
+        let mut image = Vec::new();
+        let mut testcase = load_fixtures_full().last().unwrap();
+        testcase.file.read_to_end(&mut image).unwrap();
+        image
+    }
+
+    fn check_image<S>(source: WindowSource<S>)
+    where
+        S: FileSource + 'static,
+    {
+        let mut sbi: SimpleBufferedFileSystem = SuperblockInfo::new(
+            Box::new(ImageFileSystem::try_new(UncompressedBackend::new(source)).unwrap()),
+            HashMap::new(),
+            (),
+        );
+        test_filesystem(&mut sbi, true);
+    }
+
+    #[test]
+    fn test_window_scan() {
+        let image = fixture();
+        let len = image.len() as Off;
+        // Junk in front, the image at an odd sector and trailing junk that must not be read.
+        let mut disk = vec![0xa5u8; 8192 + 512];
+        disk.extend_from_slice(&image);
+        disk.extend_from_slice(&[0x5a; 4096]);
+
+        let window = WindowSource::new(&disk[..], 8704, len);
+        let mut data = [0u8; 16];
+        assert_eq!(window.fill(&mut data, 0, len - 4), Ok(4));
+        assert_eq!(window.fill(&mut data, 0, len), Ok(0));
+        assert_eq!(window.fill(&mut data, 0, Off::MAX), Ok(0));
+        assert_eq!(window.as_buf(0, len - 4, 16).unwrap().content().len(), 4);
+
+        assert_eq!(scan(&disk, 4096), Ok(vec![]));
+        let found = scan(&disk, 512).unwrap();
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0], 8704..8704 + len);
+        assert_eq!(scan(&disk, 3), Err(EINVAL));
+        assert_eq!(probe(&disk, 8704), Ok(Some(8704..8704 + len)));
+        assert_eq!(probe(&disk, 8192), Ok(None));
+        assert_eq!(probe(&disk, Off::MAX), Ok(None));
+        check_image(WindowSource::new(disk, 8704, len));
+    }
+
+    #[test]
+    fn test_gpt_partitions() {
+        const SECTOR: usize = 512;
+        let image = fixture();
+        let lba = |sectors: usize| vec![0u8; sectors * SECTOR];
+        // Protective MBR, header, a table of 4 entries, then the data partition and the image.
+        let mut disk = lba(2048);
+        let header = &mut disk[SECTOR..2 * SECTOR];
+        header[0..8].copy_from_slice(GPT_SIGNATURE);
+        header[72..80].copy_from_slice(&2u64.to_le_bytes());
+        header[80..84].copy_from_slice(&4u32.to_le_bytes());
+        header[84..88].copy_from_slice(&128u32.to_le_bytes());
+        let sectors = image.len().div_ceil(SECTOR);
+        for (i, (first, last, name)) in [
+            (34u64, 2047u64, "data"),
+            (2048, 2047 + sectors as u64, "system"),
+        ]
+        .into_iter()
+        .enumerate()
+        {
+            let entry = &mut disk[2 * SECTOR + (i * 2 + 1) * 128..][..128];
+            entry[0..16].fill(i as u8 + 1);
+            entry[16..32].fill(0x10 + i as u8);
+            entry[32..40].copy_from_slice(&first.to_le_bytes());
+            entry[40..48].copy_from_slice(&last.to_le_bytes());
+            for (j, c) in name.encode_utf16().enumerate() {
+                entry[56 + j * 2..58 + j * 2].copy_from_slice(&c.to_le_bytes());
+            }
+        }
+        disk.extend_from_slice(&image);
+        disk.resize(disk.len().next_multiple_of(SECTOR), 0);
+
+        let partitions = gpt_partitions(&disk).unwrap();
+        assert_eq!(partitions.len(), 2);
+        assert_eq!(partitions[0].index(), 1);
+        assert_eq!(partitions[0].range(), 34 * 512..2048 * 512);
+        assert_eq!(partitions[0].name(), "data");
+        assert_eq!(partitions[1].index(), 3);
+        assert_eq!(partitions[1].type_guid(), &[2; 16]);
+        assert_eq!(partitions[1].unique_guid(), &[0x11; 16]);
+        assert_eq!(partitions[1].name(), "system");
+
+        let erofs = gpt_erofs_partitions(&disk).unwrap();
+        assert_eq!(erofs, partitions[1..]);
+        let range = erofs[0].range();
+        check_image(WindowSource::new(
+            disk.clone(),
+            range.start,
+            range.end - range.start,
+        ));
+
+        assert_eq!(gpt_partitions(&image), Err(ENODEV));
+        // A corrupted entry ending at the very last LBA.
+        let mut corrupt = disk.clone();
+        corrupt[2 * SECTOR + 128 + 40..][..8].copy_from_slice(&u64::MAX.to_le_bytes());
+        assert_eq!(gpt_partitions(&corrupt), Err(EUCLEAN));
+        disk[SECTOR + 84..SECTOR + 88].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert_eq!(gpt_partitions(&disk), Err(EUCLEAN));
+    }
+}
@@ -0,0 +1,131 @@
+// Copyright 2024 Yiyang Wu
+// SPDX-License-Identifier: MIT or GPL-2.0-or-later
+
+use alloc::vec::Vec;
+use core::mem;
+
+use super::super::alloc_helper::*;
+use super::super::cache::*;
+use super::*;
+
+/// The number of idle buffers a pool holds on to. Readers rarely keep more than one buffer alive
+/// at a time, so a few slots cover some concurrency while bounding the memory kept around.
+const POOL_SLOTS: usize = 4;
+
+/// Recycles the temporary buffers of file backed iterators, so that reads in the steady state
+/// don't allocate a fresh block each time. Buffers are handed back when dropped. Like the other
+/// caches in this crate it never waits: on contention buffers are simply allocated or freed.
+pub(crate) struct BufferPool {
+    slots: TryLock<[Vec<u8>; POOL_SLOTS]>,
+}
+
+impl BufferPool {
+    pub(crate) fn new() -> Self {
+        Self {
+            slots: TryLock::new(Default::default()),
+        }
+    }
+
+    /// Take an idle buffer, preferring one which is already large enough.
+    fn take(&self, len: usize) -> Vec<u8> {
+        let Some(mut slots) = self.slots.try_lock() else {
+            return Vec::new();
+        };
+        let slot = slots.iter().position(|s| s.capacity() >= len).or_else(|| {
+            (0..POOL_SLOTS)
+                .filter(|i| slots[*i].capacity() != 0)
+                .max_by_key(|i| slots[*i].capacity())
+        });
+        slot.map_or_else(Vec::new, |i| mem::take(&mut slots[i]))
+    }
+
+    /// Give a buffer back, replacing the smallest idle one if the pool is full.
+    fn put(&self, block: Vec<u8>) {
+        let Some(mut slots) = self.slots.try_lock() else {
+            return;
+        };
+        if let Some(slot) = slots
+            .iter_mut()
+            .min_by_key(|s| s.capacity())
+            .filter(|s| s.capacity() < block.capacity())
+        {
+            *slot = block;
+        }
+    }
+
+    /// Get a buffer of `len` bytes. The content is whatever the buffer held before unless
+    /// `zeroed` is set, so callers are expected to overwrite all of it.
+    pub(crate) fn get(&self, len: usize, zeroed: bool) -> PosixResult<PooledBuffer<'_>> {
+        let mut block = self.take(len);
+        if zeroed {
+            block.clear();
+        }
+        resize_vec(&mut block, len, 0)?;
+        Ok(PooledBuffer { block, pool: self })
+    }
+}
+
+/// A buffer borrowed from a [`BufferPool`] which goes back to it once dropped.
+pub(crate) struct PooledBuffer<'a> {
+    block: Vec<u8>,
+    pool: &'a BufferPool,
+}
+
+impl<'a> PooledBuffer<'a> {
+    pub(crate) fn as_mut_slice(&mut self) -> &mut [u8] {
+        &mut self.block
+    }
+}
+
+impl<'a> Buffer for PooledBuffer<'a> {
+    fn content(&self) -> &[u8] {
+        &self.block
+    }
+}
+
+impl<'a> Drop for PooledBuffer<'a> {
+    fn drop(&mut self) {
+        self.pool.put(mem::take(&mut self.block));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_buffer_pool_reuse() {
+        let pool = BufferPool::new();
+        let mut buf = pool.get(4096, false).unwrap();
+        buf.as_mut_slice().fill(0xaa);
+        let ptr = buf.content().as_ptr();
+        drop(buf);
+
+        // The same block comes back for smaller and equal sizes, zeroed only on request.
+        let buf = pool.get(100, false).unwrap();
+        assert_eq!(buf.content().as_ptr(), ptr);
+        assert_eq!(buf.content(), [0xaa; 100]);
+        drop(buf);
+        let buf = pool.get(4096, true).unwrap();
+        assert_eq!(buf.content().as_ptr(), ptr);
+        assert_eq!(buf.content(), [0; 4096]);
+
+        // While it is taken, others are allocated and all of them are kept once returned.
+        let bufs = (0..POOL_SLOTS + 1)
+            .map(|_| pool.get(512, false).unwrap())
+            .collect::<Vec<_>>();
+        assert!(bufs.iter().all(|b| b.content().as_ptr() != ptr));
+        drop(bufs);
+        drop(buf);
+        let slots = pool.slots.try_lock().unwrap();
+        assert!(slots.iter().all(|s| s.capacity() != 0));
+        assert!(slots.iter().any(|s| s.as_ptr() == ptr));
+        drop(slots);
+
+        // Contention never blocks nor fails.
+        let guard = pool.slots.try_lock().unwrap();
+        assert_eq!(pool.get(16, true).unwrap().content(), [0; 16]);
+        drop(guard);
+        assert_eq!(pool.get(4096, false).unwrap().content().as_ptr(), ptr);
+    }
+}
@@ -6,13 +6,14 @@ pub(crate) mod temp_iter;
 mod traits;
 pub(crate) use traits::*;
 
+use super::super::errnos::*;
 pub(crate) use super::*;
 
 /// Represents a skippable continuous buffer iterator. This is used primarily for reading the
 /// extended attributes. Since the key-value is flattened out in its original format.
 pub(crate) struct SkippableContinuousIter<'a> {
     iter: Box<dyn ContinuousBufferIter<'a> + 'a>,
-    data: Box<dyn Buffer + 'a>,
+    data: BlockBuffer<'a>,
     cur: usize,
 }
 
@@ -48,7 +49,7 @@ impl<'a> SkippableContinuousIter<'a> {
         if iter.eof() {
             return Ok(None);
         }
-        let data = iter.next().unwrap()?;
+        let data = iter.next().ok_or(EUCLEAN)??;
         Ok(Some(Self { iter, data, cur: 0 }))
     }
     pub(crate) fn skip(&mut self, offset: Off) -> PosixResult<()> {
@@ -56,9 +57,14 @@ impl<'a> SkippableContinuousIter<'a> {
         if offset as usize <= dlen {
             self.cur += offset as usize;
         } else {
-            self.cur = 0;
-            self.iter.advance_off(dlen as Off);
-            self.data = self.iter.next().unwrap()?;
+            self.iter.advance_off(offset - dlen as Off)?;
+            match self.iter.next() {
+                Some(data) => {
+                    self.data = data?;
+                    self.cur = 0;
+                }
+                None => self.cur = self.data.content().len(),
+            }
         }
         Ok(())
     }
@@ -75,7 +81,7 @@ impl<'a> SkippableContinuousIter<'a> {
             bcur += dlen;
             while bcur < blen {
                 self.cur = 0;
-                self.data = self.iter.next().unwrap()?;
+                self.data = self.iter.next().ok_or(EUCLEAN)??;
                 dlen = self.data.content().len();
                 if dlen >= blen - bcur {
                     buf[bcur..].copy_from_slice(&self.data.content()[..(blen - bcur)]);
@@ -110,7 +116,7 @@ impl<'a> SkippableContinuousIter<'a> {
             }
             while bcur < blen {
                 self.cur = 0;
-                self.data = self.iter.next().unwrap()?;
+                self.data = self.iter.next().ok_or(EUCLEAN)??;
                 let dlen = self.data.content().len();
                 let clen = dlen.min(blen - bcur);
                 if !cmp_with_cursor_move(self.data.content(), buf, &mut self.cur, &mut bcur, clen) {
@@ -39,18 +39,24 @@ where
     B: MemoryBackend<'a>,
     I: Inode,
 {
-    type Item = PosixResult<Box<dyn Buffer + 'a>>;
+    type Item = PosixResult<BlockBuffer<'a>>;
     fn next(&mut self) -> Option<Self::Item> {
         match self.map_iter.next() {
             Some(map) => match map {
+                Ok(m) if m.is_hole() => {
+                    let len = m.logical.len as usize;
+                    Some(vec_with_capacity(len).and_then(|mut block| {
+                        resize_vec(&mut block, len, 0)?;
+                        Ok(TempBuffer::new(block, 0, len).into())
+                    }))
+                }
                 Ok(m) => {
-                    let accessor = self.sb.blk_access(m.physical.start);
-                    let len = m.physical.len.min(accessor.len);
+                    let len = m.logical.len;
                     match self
                         .backend
                         .as_buf(m.device_id as i32, m.physical.start, len)
                     {
-                        Ok(buf) => Some(heap_alloc(buf).map(|v| v as Box<dyn Buffer + 'a>)),
+                        Ok(buf) => Some(Ok(buf.into())),
                         Err(e) => Some(Err(e)),
                     }
                 }
@@ -97,7 +103,7 @@ impl<'a, B> Iterator for ContinuousRefIter<'a, B>
 where
     B: MemoryBackend<'a>,
 {
-    type Item = PosixResult<Box<dyn Buffer + 'a>>;
+    type Item = PosixResult<BlockBuffer<'a>>;
     fn next(&mut self) -> Option<Self::Item> {
         if self.len == 0 {
             return None;
@@ -109,7 +115,7 @@ where
             |buf| {
                 self.offset += len;
                 self.len -= len;
-                Some(heap_alloc(buf).map(|v| v as Box<dyn Buffer + 'a>))
+                Some(Ok(buf.into()))
             },
         );
         result
@@ -120,9 +126,13 @@ impl<'a, B> ContinuousBufferIter<'a> for ContinuousRefIter<'a, B>
 where
     B: MemoryBackend<'a>,
 {
-    fn advance_off(&mut self, offset: Off) {
+    fn advance_off(&mut self, offset: Off) -> PosixResult<()> {
+        if offset > self.len {
+            return Err(EUCLEAN);
+        }
         self.offset += offset;
-        self.len -= offset
+        self.len -= offset;
+        Ok(())
     }
     fn eof(&self) -> bool {
         self.len == 0
@@ -1,8 +1,22 @@
 // Copyright 2024 Yiyang Wu
 // SPDX-License-Identifier: MIT or GPL-2.0-or-later
 
+use super::super::pool::*;
 use super::super::*;
 use super::traits::*;
+use super::*;
+
+/// Fill a pooled block, zeroing whatever a short read leaves behind since the block may still
+/// hold the data of an earlier read.
+fn fill_block<B>(backend: &B, block: &mut [u8], device_id: i32, offset: Off) -> PosixResult<()>
+where
+    B: FileBackend,
+{
+    let len = backend.fill(block, device_id, offset)? as usize;
+    let len = len.min(block.len());
+    block[len..].fill(0);
+    Ok(())
+}
 
 pub(crate) struct TempBufferMapIter<'a, 'b, FS, B, I>
 where
@@ -12,6 +26,7 @@ where
 {
     sb: &'a SuperBlock,
     backend: &'a B,
+    pool: &'a BufferPool,
     map_iter: MapIter<'a, 'b, FS, I>,
 }
 
@@ -24,21 +39,27 @@ where
     pub(crate) fn new(
         sb: &'a SuperBlock,
         backend: &'a B,
+        pool: &'a BufferPool,
         map_iter: MapIter<'a, 'b, FS, I>,
     ) -> Self {
         Self {
             sb,
             backend,
+            pool,
             map_iter,
         }
     }
-    fn try_yield(&mut self, map: Map) -> PosixResult<Box<dyn Buffer + 'a>> {
-        let accessor = self.sb.blk_access(map.physical.start);
-        let len = accessor.len.min(map.physical.len);
-        let mut block = vec_with_capacity(len as usize).unwrap();
-        self.backend
-            .fill(&mut block, map.device_id as i32, map.physical.start)?;
-        heap_alloc(TempBuffer::new(block, 0, len as usize)).map(|v| v as Box<dyn Buffer + 'a>)
+    fn try_yield(&mut self, map: Map) -> PosixResult<BlockBuffer<'a>> {
+        let mut block = self.pool.get(map.logical.len as usize, map.is_hole())?;
+        if !map.is_hole() {
+            fill_block(
+                self.backend,
+                block.as_mut_slice(),
+                map.device_id as i32,
+                map.physical.start,
+            )?;
+        }
+        Ok(block.into())
     }
 }
 
@@ -48,7 +69,7 @@ where
     B: FileBackend,
     I: Inode,
 {
-    type Item = PosixResult<Box<dyn Buffer + 'a>>;
+    type Item = PosixResult<BlockBuffer<'a>>;
     fn next(&mut self) -> Option<Self::Item> {
         match self.map_iter.next() {
             Some(map) => match map {
@@ -74,6 +95,7 @@ where
 {
     sb: &'a SuperBlock,
     backend: &'a B,
+    pool: &'a BufferPool,
     offset: Off,
     len: Off,
 }
@@ -82,22 +104,29 @@ impl<'a, B> ContinuousTempBufferIter<'a, B>
 where
     B: FileBackend,
 {
-    pub(crate) fn new(sb: &'a SuperBlock, backend: &'a B, offset: Off, len: Off) -> Self {
+    pub(crate) fn new(
+        sb: &'a SuperBlock,
+        backend: &'a B,
+        pool: &'a BufferPool,
+        offset: Off,
+        len: Off,
+    ) -> Self {
         Self {
             sb,
             backend,
+            pool,
             offset,
             len,
         }
     }
-    fn try_yield(&mut self) -> PosixResult<Box<dyn Buffer + 'a>> {
+    fn try_yield(&mut self) -> PosixResult<BlockBuffer<'a>> {
         let accessor = self.sb.blk_access(self.offset);
         let len = self.len.min(accessor.len);
-        let mut block = vec_with_capacity(len as usize)?;
-        self.backend.fill(&mut block, 0, self.offset)?;
+        let mut block = self.pool.get(len as usize, false)?;
+        fill_block(self.backend, block.as_mut_slice(), 0, self.offset)?;
         self.offset += len;
         self.len -= len;
-        heap_alloc(TempBuffer::new(block, 0, len as usize)).map(|v| v as Box<dyn Buffer + 'a>)
+        Ok(block.into())
     }
 }
 
@@ -105,7 +134,7 @@ impl<'a, B> Iterator for ContinuousTempBufferIter<'a, B>
 where
     B: FileBackend,
 {
-    type Item = PosixResult<Box<dyn Buffer + 'a>>;
+    type Item = PosixResult<BlockBuffer<'a>>;
     fn next(&mut self) -> Option<Self::Item> {
         if self.len == 0 {
             return None;
@@ -118,9 +147,13 @@ impl<'a, B> ContinuousBufferIter<'a> for ContinuousTempBufferIter<'a, B>
 where
     B: FileBackend,
 {
-    fn advance_off(&mut self, offset: Off) {
+    fn advance_off(&mut self, offset: Off) -> PosixResult<()> {
+        if offset > self.len {
+            return Err(EUCLEAN);
+        }
         self.offset += offset;
         self.len -= offset;
+        Ok(())
     }
     fn eof(&self) -> bool {
         self.len == 0
@@ -5,11 +5,12 @@ use super::super::*;
 
 /// Represents a basic iterator over a range of bytes from data backends.
 /// The access order is guided by the block maps from the filesystem.
-pub trait BufferMapIter<'a>: Iterator<Item = PosixResult<Box<dyn Buffer + 'a>>> {}
+pub trait BufferMapIter<'a>: Iterator<Item = PosixResult<BlockBuffer<'a>>> {}
 
 /// Represents a basic iterator over a range of bytes from data backends.
 /// Note that this is skippable and can be used to move the iterator's cursor forward.
-pub trait ContinuousBufferIter<'a>: Iterator<Item = PosixResult<Box<dyn Buffer + 'a>>> {
-    fn advance_off(&mut self, offset: Off);
+pub trait ContinuousBufferIter<'a>: Iterator<Item = PosixResult<BlockBuffer<'a>>> {
+    /// Move the cursor forward. Moving beyond the end of the range fails with EUCLEAN.
+    fn advance_off(&mut self, offset: Off) -> PosixResult<()>;
     fn eof(&self) -> bool;
 }
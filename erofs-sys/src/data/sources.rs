@@ -0,0 +1,224 @@
+// Copyright 2024 Yiyang Wu
+// SPDX-License-Identifier: MIT or GPL-2.0-or-later
+
+use alloc::vec::Vec;
+
+use super::super::errnos::*;
+use super::super::*;
+use super::*;
+
+/// Copy as much of `image` starting at `offset` as fits into `data`. Reading past the end of the
+/// image is not an error, it just returns less.
+fn fill_from_slice(image: &[u8], data: &mut [u8], offset: Off) -> PosixResult<u64> {
+    let start = usize::try_from(offset).map_or(image.len(), |o| o.min(image.len()));
+    let len = data.len().min(image.len() - start);
+    data[..len].copy_from_slice(&image[start..start + len]);
+    Ok(len as u64)
+}
+
+/// Borrow at most `len` bytes of `image` starting at `offset`.
+fn buf_from_slice(image: &[u8], offset: Off, len: Off) -> PosixResult<RefBuffer<'_>> {
+    let start = usize::try_from(offset).map_or(image.len(), |o| o.min(image.len()));
+    let len = usize::try_from(len).map_or(image.len() - start, |l| l.min(image.len() - start));
+    Ok(RefBuffer::new(&image[start..start + len], 0, len, |_| {}))
+}
+
+impl Source for &[u8] {
+    fn fill(&self, data: &mut [u8], _device_id: i32, offset: Off) -> PosixResult<u64> {
+        fill_from_slice(self, data, offset)
+    }
+}
+
+impl FileSource for &[u8] {}
+
+impl<'a> PageSource<'a> for &[u8] {
+    fn as_buf(&'a self, _device_id: i32, offset: Off, len: Off) -> PosixResult<RefBuffer<'a>> {
+        buf_from_slice(self, offset, len)
+    }
+}
+
+impl Source for Vec<u8> {
+    fn fill(&self, data: &mut [u8], _device_id: i32, offset: Off) -> PosixResult<u64> {
+        fill_from_slice(self, data, offset)
+    }
+}
+
+impl FileSource for Vec<u8> {}
+
+impl<'a> PageSource<'a> for Vec<u8> {
+    fn as_buf(&'a self, _device_id: i32, offset: Off, len: Off) -> PosixResult<RefBuffer<'a>> {
+        buf_from_slice(self, offset, len)
+    }
+}
+
+/// A window of `len` bytes starting at `start` of another source, for images embedded in larger
+/// files such as disk images or binaries. Reads never leave the window.
+pub struct WindowSource<S>
+where
+    S: Source,
+{
+    source: S,
+    start: Off,
+    len: Off,
+}
+
+impl<S> WindowSource<S>
+where
+    S: Source,
+{
+    /// Expose `len` bytes of `source` starting at `start` as a source of its own.
+    pub fn new(source: S, start: Off, len: Off) -> Self {
+        Self { source, start, len }
+    }
+
+    /// The offset of the window within the underlying source.
+    pub fn start(&self) -> Off {
+        self.start
+    }
+
+    /// The length of the window.
+    pub fn len(&self) -> Off {
+        self.len
+    }
+
+    /// Whether the window is empty.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Give back the underlying source.
+    pub fn into_inner(self) -> S {
+        self.source
+    }
+
+    /// The number of bytes left in the window from `offset` on, at most `len`, and where they
+    /// start in the underlying source.
+    fn clip(&self, offset: Off, len: Off) -> PosixResult<(Off, Off)> {
+        let len = len.min(self.len.saturating_sub(offset));
+        if len == 0 {
+            return Ok((self.start, 0));
+        }
+        Ok((self.start.checked_add(offset).ok_or(EINVAL)?, len))
+    }
+}
+
+impl<S> Source for WindowSource<S>
+where
+    S: Source,
+{
+    fn fill(&self, data: &mut [u8], device_id: i32, offset: Off) -> PosixResult<u64> {
+        let (start, len) = self.clip(offset, data.len() as Off)?;
+        if len == 0 {
+            return Ok(0);
+        }
+        self.source
+            .fill(&mut data[..len as usize], device_id, start)
+    }
+}
+
+impl<S> FileSource for WindowSource<S> where S: FileSource {}
+
+impl<'a, S> PageSource<'a> for WindowSource<S>
+where
+    S: PageSource<'a>,
+{
+    fn as_buf(&'a self, device_id: i32, offset: Off, len: Off) -> PosixResult<RefBuffer<'a>> {
+        let (start, len) = self.clip(offset, len)?;
+        self.source.as_buf(device_id, start, len)
+    }
+}
+
+// Tests always have std at hand, so they share these instead of rolling their own.
+#[cfg(any(feature = "std", test))]
+mod file {
+    use super::*;
+    use std::fs::File;
+    use std::io::ErrorKind;
+    use std::os::unix::fs::FileExt;
+
+    impl Source for File {
+        /// Keeps reading until `data` is full or the end of the file is reached, so that a short
+        /// count always means EOF.
+        fn fill(&self, data: &mut [u8], _device_id: i32, offset: Off) -> PosixResult<u64> {
+            let mut done = 0;
+            while done < data.len() {
+                match self.read_at(&mut data[done..], offset + done as Off) {
+                    Ok(0) => break,
+                    Ok(len) => done += len,
+                    Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                    Err(e) => return Err(e.into()),
+                }
+            }
+            Ok(done as u64)
+        }
+    }
+
+    impl FileSource for File {}
+}
+
+#[cfg(any(feature = "mmap", test))]
+mod mmap {
+    use super::*;
+    use memmap2::{Mmap, MmapMut};
+
+    impl Source for Mmap {
+        fn fill(&self, data: &mut [u8], _device_id: i32, offset: Off) -> PosixResult<u64> {
+            fill_from_slice(self, data, offset)
+        }
+    }
+
+    impl FileSource for Mmap {}
+
+    impl<'a> PageSource<'a> for Mmap {
+        fn as_buf(&'a self, _device_id: i32, offset: Off, len: Off) -> PosixResult<RefBuffer<'a>> {
+            buf_from_slice(self, offset, len)
+        }
+    }
+
+    impl Source for MmapMut {
+        fn fill(&self, data: &mut [u8], _device_id: i32, offset: Off) -> PosixResult<u64> {
+            fill_from_slice(self, data, offset)
+        }
+    }
+
+    impl FileSource for MmapMut {}
+
+    impl<'a> PageSource<'a> for MmapMut {
+        fn as_buf(&'a self, _device_id: i32, offset: Off, len: Off) -> PosixResult<RefBuffer<'a>> {
+            buf_from_slice(self, offset, len)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::File;
+    use std::io::Write;
+
+    #[test]
+    fn test_sources_short_reads() {
+        let image: Vec<u8> = (0..=255).collect();
+        let mut path = std::env::temp_dir();
+        path.push(std::format!("erofs-sys-source-{}", std::process::id()));
+        File::create(&path).unwrap().write_all(&image).unwrap();
+        let file = File::open(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let map = unsafe { memmap2::Mmap::map(&file).unwrap() };
+
+        let sources: [&dyn Source; 4] = [&&image[..], &image, &file, &map];
+        for source in sources {
+            let mut data = [0u8; 16];
+            assert_eq!(source.fill(&mut data, 0, 8), Ok(16));
+            assert_eq!(data[..], image[8..24]);
+            assert_eq!(source.fill(&mut data, 0, 250), Ok(6));
+            assert_eq!(data[..6], image[250..]);
+            assert_eq!(source.fill(&mut data, 0, 256), Ok(0));
+        }
+        assert_eq!(image.fill(&mut [0u8; 16], 0, Off::MAX), Ok(0));
+
+        let buf = image.as_buf(0, 250, 16).unwrap();
+        assert_eq!(buf.content(), &image[250..]);
+        assert!(map.as_buf(0, 300, 16).unwrap().content().is_empty());
+    }
+}
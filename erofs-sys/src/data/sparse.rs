@@ -0,0 +1,308 @@
+// Copyright 2024 Yiyang Wu
+// SPDX-License-Identifier: MIT or GPL-2.0-or-later
+
+use alloc::vec::Vec;
+
+use super::super::alloc_helper::*;
+use super::super::errnos::*;
+use super::super::*;
+use super::*;
+
+const SPARSE_HEADER_MAGIC: u32 = 0xed26_ff3a;
+const SPARSE_HEADER_SIZE: usize = 28;
+const CHUNK_HEADER_SIZE: usize = 12;
+const CHUNK_TYPE_RAW: u16 = 0xcac1;
+const CHUNK_TYPE_FILL: u16 = 0xcac2;
+const CHUNK_TYPE_DONT_CARE: u16 = 0xcac3;
+const CHUNK_TYPE_CRC32: u16 = 0xcac4;
+
+#[derive(Debug, Clone, Copy)]
+enum ChunkData {
+    /// The blocks are stored as is at this offset of the sparse file.
+    Raw(Off),
+    /// Every block repeats the same four bytes.
+    Fill([u8; 4]),
+    /// The content is undefined, served as zeros.
+    DontCare,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Chunk {
+    /// The offset of the chunk in the expanded image.
+    start: Off,
+    len: Off,
+    data: ChunkData,
+}
+
+/// Serve an image stored in the Android sparse format as if it was expanded, so that it can be
+/// opened without writing out the raw image first. The chunk table is read once up front.
+pub struct SparseSource<S>
+where
+    S: Source,
+{
+    source: S,
+    chunks: Vec<Chunk>,
+    len: Off,
+}
+
+fn u16_at(buf: &[u8], pos: usize) -> u16 {
+    u16::from_le_bytes([buf[pos], buf[pos + 1]])
+}
+
+fn u32_at(buf: &[u8], pos: usize) -> u32 {
+    u32::from_le_bytes([buf[pos], buf[pos + 1], buf[pos + 2], buf[pos + 3]])
+}
+
+fn read_exact(source: &dyn Source, buf: &mut [u8], offset: Off) -> PosixResult<()> {
+    if source.fill(buf, 0, offset)? != buf.len() as u64 {
+        return Err(EUCLEAN);
+    }
+    Ok(())
+}
+
+/// Whether `source` starts with a sparse image header.
+pub fn is_sparse(source: &dyn Source) -> PosixResult<bool> {
+    let mut magic = [0u8; 4];
+    Ok(source.fill(&mut magic, 0, 0)? == 4 && u32::from_le_bytes(magic) == SPARSE_HEADER_MAGIC)
+}
+
+impl<S> SparseSource<S>
+where
+    S: Source,
+{
+    /// Parse the sparse header and chunk table of `source`. Fails with EINVAL if it isn't a
+    /// sparse image and with EUCLEAN if the chunk table doesn't add up.
+    pub fn new(source: S) -> PosixResult<Self> {
+        let mut header = [0u8; SPARSE_HEADER_SIZE];
+        if source.fill(&mut header, 0, 0)? != SPARSE_HEADER_SIZE as u64
+            || u32_at(&header, 0) != SPARSE_HEADER_MAGIC
+            || u16_at(&header, 4) != 1
+        {
+            return Err(EINVAL);
+        }
+        let header_size = u16_at(&header, 8) as Off;
+        let chunk_header_size = u16_at(&header, 10) as Off;
+        let blksz = u32_at(&header, 12) as Off;
+        let blocks = u32_at(&header, 16) as Off;
+        let count = u32_at(&header, 20);
+        if header_size < SPARSE_HEADER_SIZE as Off
+            || chunk_header_size < CHUNK_HEADER_SIZE as Off
+            || blksz == 0
+            || blksz % 4 != 0
+        {
+            return Err(EUCLEAN);
+        }
+
+        let mut chunks = Vec::new();
+        let mut pos = header_size;
+        let mut start: Off = 0;
+        for _ in 0..count {
+            let mut chunk = [0u8; CHUNK_HEADER_SIZE];
+            read_exact(&source, &mut chunk, pos)?;
+            let kind = u16_at(&chunk, 0);
+            let len = u32_at(&chunk, 4) as Off * blksz;
+            let total = u32_at(&chunk, 8) as Off;
+            let body = total.checked_sub(chunk_header_size).ok_or(EUCLEAN)?;
+            let data = match kind {
+                CHUNK_TYPE_RAW if body == len => Some(ChunkData::Raw(pos + chunk_header_size)),
+                CHUNK_TYPE_FILL if body == 4 => {
+                    let mut pattern = [0u8; 4];
+                    read_exact(&source, &mut pattern, pos + chunk_header_size)?;
+                    Some(ChunkData::Fill(pattern))
+                }
+                CHUNK_TYPE_DONT_CARE if body == 0 => Some(ChunkData::DontCare),
+                // The checksum covers the whole image, there is nothing to check it against.
+                CHUNK_TYPE_CRC32 if body == 4 => None,
+                _ => return Err(EUCLEAN),
+            };
+            if let Some(data) = data {
+                if len > 0 {
+                    push_vec(&mut chunks, Chunk { start, len, data })?;
+                }
+                start = start.checked_add(len).ok_or(EUCLEAN)?;
+            }
+            pos = pos.checked_add(total).ok_or(EUCLEAN)?;
+        }
+        if start != blocks * blksz {
+            return Err(EUCLEAN);
+        }
+        Ok(Self {
+            source,
+            chunks,
+            len: start,
+        })
+    }
+
+    /// The size of the expanded image.
+    pub fn len(&self) -> Off {
+        self.len
+    }
+
+    /// Whether the expanded image is empty.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Give back the underlying source.
+    pub fn into_inner(self) -> S {
+        self.source
+    }
+}
+
+impl<S> Source for SparseSource<S>
+where
+    S: Source,
+{
+    fn fill(&self, data: &mut [u8], device_id: i32, offset: Off) -> PosixResult<u64> {
+        let mut done = 0;
+        let mut index = self.chunks.partition_point(|c| c.start + c.len <= offset);
+        while done < data.len() {
+            let Some(chunk) = self.chunks.get(index) else {
+                break;
+            };
+            let pos = offset + done as Off;
+            let skip = pos - chunk.start;
+            let len = (chunk.len - skip).min((data.len() - done) as Off) as usize;
+            let out = &mut data[done..done + len];
+            match chunk.data {
+                ChunkData::Raw(at) => {
+                    let read = self.source.fill(out, device_id, at + skip)? as usize;
+                    done += read;
+                    // The sparse file is truncated, the rest of the image is gone too.
+                    if read < len {
+                        break;
+                    }
+                    index += 1;
+                    continue;
+                }
+                ChunkData::Fill(pattern) => {
+                    // Chunks are block aligned and blocks a multiple of four bytes long.
+                    for (i, b) in out.iter_mut().enumerate() {
+                        *b = pattern[(pos as usize + i) % 4];
+                    }
+                }
+                ChunkData::DontCare => out.fill(0),
+            }
+            done += len;
+            index += 1;
+        }
+        Ok(done as u64)
+    }
+}
+
+impl<S> FileSource for SparseSource<S> where S: FileSource {}
+
+#[cfg(test)]
+mod tests {
+    use super::super::super::file::ImageFileSystem;
+    use super::super::super::superblock::tests::*;
+    use super::super::backends::uncompressed::*;
+    use super::*;
+    use alloc::boxed::Box;
+    use std::collections::HashMap;
+    use std::io::Read;
+    use std::vec;
+
+    const BLKSZ: usize = 4096;
+
+    fn header(blocks: u32, chunks: u32) -> Vec<u8> {
+        let mut header = Vec::new();
+        header.extend_from_slice(&SPARSE_HEADER_MAGIC.to_le_bytes());
+        header.extend_from_slice(&1u16.to_le_bytes());
+        header.extend_from_slice(&0u16.to_le_bytes());
+        header.extend_from_slice(&(SPARSE_HEADER_SIZE as u16).to_le_bytes());
+        header.extend_from_slice(&(CHUNK_HEADER_SIZE as u16).to_le_bytes());
+        header.extend_from_slice(&(BLKSZ as u32).to_le_bytes());
+        header.extend_from_slice(&blocks.to_le_bytes());
+        header.extend_from_slice(&chunks.to_le_bytes());
+        header.extend_from_slice(&0u32.to_le_bytes());
+        header
+    }
+
+    fn chunk(sparse: &mut Vec<u8>, kind: u16, blocks: u32, body: &[u8]) {
+        sparse.extend_from_slice(&kind.to_le_bytes());
+        sparse.extend_from_slice(&0u16.to_le_bytes());
+        sparse.extend_from_slice(&blocks.to_le_bytes());
+        sparse.extend_from_slice(&((CHUNK_HEADER_SIZE + body.len()) as u32).to_le_bytes());
+        sparse.extend_from_slice(body);
+    }
+
+    /// Store zero blocks as don't care, uniform blocks as fill and everything else as one raw
+    /// chunk per block, with a checksum chunk thrown in.
+    fn sparsify(image: &[u8]) -> Vec<u8> {
+        let blocks = image.chunks(BLKSZ).collect::<Vec<_>>();
+        let mut sparse = header(blocks.len() as u32, blocks.len() as u32 + 1);
+        chunk(&mut sparse, CHUNK_TYPE_CRC32, 0, &[0; 4]);
+        for block in blocks {
+            if block.iter().all(|b| *b == 0) {
+                chunk(&mut sparse, CHUNK_TYPE_DONT_CARE, 1, &[]);
+            } else if block.chunks(4).all(|w| w == &block[..4]) {
+                chunk(&mut sparse, CHUNK_TYPE_FILL, 1, &block[..4]);
+            } else {
+                chunk(&mut sparse, CHUNK_TYPE_RAW, 1, block);
+            }
+        }
+        sparse
+    }
+
+    #[test]
+    fn test_sparse_fixtures() {
+        for mut testcase in load_fixtures_full().chain(load_fixtures_noxattr()) {
+            let mut image = Vec::new();
+            testcase.file.read_to_end(&mut image).unwrap();
+            image.resize(image.len().next_multiple_of(BLKSZ), 0);
+            let sparse = sparsify(&image);
+            assert!(is_sparse(&sparse).unwrap());
+            assert!(!is_sparse(&image).unwrap());
+            let source = SparseSource::new(sparse).unwrap();
+            assert_eq!(source.len(), image.len() as Off);
+            let mut expanded = vec![0u8; image.len() + 100];
+            assert_eq!(source.fill(&mut expanded, 0, 0), Ok(image.len() as u64));
+            assert_eq!(expanded[..image.len()], image[..]);
+
+            let mut sbi: SimpleBufferedFileSystem = SuperblockInfo::new(
+                Box::new(ImageFileSystem::try_new(UncompressedBackend::new(source)).unwrap()),
+                HashMap::new(),
+                (),
+            );
+            test_filesystem(&mut sbi, testcase.xattrs);
+        }
+    }
+
+    #[test]
+    fn test_sparse_chunks() {
+        let mut sparse = header(4, 3);
+        chunk(&mut sparse, CHUNK_TYPE_FILL, 2, &[1, 2, 3, 4]);
+        chunk(&mut sparse, CHUNK_TYPE_DONT_CARE, 1, &[]);
+        chunk(&mut sparse, CHUNK_TYPE_RAW, 1, &[0xaa; BLKSZ]);
+        let source = SparseSource::new(sparse.clone()).unwrap();
+        assert_eq!(source.len(), 4 * BLKSZ as Off);
+
+        // A read spanning all three chunks.
+        let mut data = [0u8; 2 * BLKSZ];
+        assert_eq!(
+            source.fill(&mut data, 0, BLKSZ as Off + 2),
+            Ok(2 * BLKSZ as u64)
+        );
+        assert_eq!(data[..4], [3, 4, 1, 2]);
+        assert!(data[BLKSZ - 2..2 * BLKSZ - 2].iter().all(|b| *b == 0));
+        assert_eq!(data[2 * BLKSZ - 2..], [0xaa; 2]);
+        assert_eq!(source.fill(&mut data, 0, 4 * BLKSZ as Off - 1), Ok(1));
+        assert_eq!(source.fill(&mut data, 0, Off::MAX), Ok(0));
+
+        // A truncated raw chunk cuts the image short.
+        let truncated = SparseSource::new(sparse[..sparse.len() - 100].to_vec()).unwrap();
+        assert_eq!(
+            truncated.fill(&mut data, 0, 2 * BLKSZ as Off),
+            Ok(2 * BLKSZ as u64 - 100)
+        );
+
+        assert_eq!(SparseSource::new(vec![0u8; 64]).err(), Some(EINVAL));
+        let mut wrong = header(5, 3);
+        wrong.extend_from_slice(&sparse[SPARSE_HEADER_SIZE..]);
+        assert_eq!(SparseSource::new(wrong).err(), Some(EUCLEAN));
+        let mut missing = header(4, 4);
+        missing.extend_from_slice(&sparse[SPARSE_HEADER_SIZE..]);
+        assert_eq!(SparseSource::new(missing).err(), Some(EUCLEAN));
+    }
+}
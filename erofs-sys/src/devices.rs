@@ -67,7 +67,7 @@ pub(crate) fn get_device_infos<'a>(
     let mask = if specs.is_empty() {
         0
     } else {
-        (1 << (specs.len().ilog2() + 1)) - 1
+        ((1u32 << (specs.len().ilog2() + 1)) - 1) as u16
     };
 
     Ok(DeviceInfo { mask, specs })
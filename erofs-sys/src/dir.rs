@@ -3,8 +3,27 @@
 
 /// On-disk Directory Descriptor Format for EROFS
 /// Documented on [EROFS Directory](https://erofs.docs.kernel.org/en/latest/core_ondisk.html#directories)
+use alloc::vec::Vec;
+use core::cmp::Ordering;
 use core::mem::size_of;
 
+use super::alloc_helper::*;
+use super::data::*;
+use super::errnos::*;
+use super::inode::*;
+use super::superblock::*;
+use super::*;
+use crate::round;
+
+pub(crate) const EROFS_FT_UNKNOWN: u8 = 0;
+pub(crate) const EROFS_FT_REG_FILE: u8 = 1;
+pub(crate) const EROFS_FT_DIR: u8 = 2;
+pub(crate) const EROFS_FT_CHRDEV: u8 = 3;
+pub(crate) const EROFS_FT_BLKDEV: u8 = 4;
+pub(crate) const EROFS_FT_FIFO: u8 = 5;
+pub(crate) const EROFS_FT_SOCK: u8 = 6;
+pub(crate) const EROFS_FT_SYMLINK: u8 = 7;
+
 /// DirentDesc
 #[repr(C, packed)]
 #[derive(Debug, Clone, Copy)]
@@ -39,67 +58,181 @@ impl From<[u8; size_of::<DirentDesc>()]> for DirentDesc {
 
 /// Create a collection of directory entries from a buffer.
 /// This is a helper struct to iterate over directory entries.
+/// Every entry is validated before being yielded, and a corrupted block yields EUCLEAN once and
+/// then ends the iteration.
 pub struct DirCollection<'a> {
     data: &'a [u8],
     offset: usize,
-    total: usize,
+    total: PosixResult<usize>,
 }
 
 impl<'a> DirCollection<'a> {
     pub(crate) fn new(buffer: &'a [u8]) -> Self {
-        let desc_data: [u8; size_of::<DirentDesc>()] =
-            buffer[..size_of::<DirentDesc>()].try_into().unwrap();
-        let desc: DirentDesc = desc_data.into();
         Self {
             data: buffer,
             offset: 0,
-            total: desc.nameoff as usize / core::mem::size_of::<DirentDesc>(),
-        }
-    }
-    pub(crate) fn dirent(&self, index: usize) -> Option<Dirent<'a>> {
-        //SAFETY: Note that DirentDesc is yet another ffi-safe type and the size of Block is larger
-        //than that of DirentDesc. It's safe to allow this unsafe cast.
-        let descs: &'a [[u8; size_of::<DirentDesc>()]] =
-            unsafe { core::slice::from_raw_parts(self.data.as_ptr().cast(), self.total) };
-        if index >= self.total {
-            None
-        } else if index == self.total - 1 {
-            let desc = DirentDesc::from(descs[index]);
-            let len = self.data.len() - desc.nameoff as usize;
-            Some(Dirent {
-                desc,
-                name: &self.data[desc.nameoff as usize..(desc.nameoff as usize) + len],
-            })
+            total: Self::count(buffer),
+        }
+    }
+    /// The nameoff of the first entry tells how many descriptors the block holds, so it has to
+    /// be a whole number of descriptors which fits in the block together with some names.
+    fn count(buffer: &[u8]) -> PosixResult<usize> {
+        const UNIT: usize = size_of::<DirentDesc>();
+        let nameoff = Self::desc_at(buffer, 0)?.nameoff as usize;
+        if nameoff < UNIT || nameoff % UNIT != 0 || nameoff >= buffer.len() {
+            return Err(EUCLEAN);
+        }
+        Ok(nameoff / UNIT)
+    }
+    fn desc_at(buffer: &[u8], index: usize) -> PosixResult<DirentDesc> {
+        const UNIT: usize = size_of::<DirentDesc>();
+        buffer
+            .get(index * UNIT..(index + 1) * UNIT)
+            .and_then(|raw| <[u8; UNIT]>::try_from(raw).ok())
+            .map(DirentDesc::from)
+            .ok_or(EUCLEAN)
+    }
+    pub(crate) fn dirent(&self, index: usize) -> PosixResult<Option<Dirent<'a>>> {
+        let total = self.total?;
+        if index >= total {
+            return Ok(None);
+        }
+        let desc = Self::desc_at(self.data, index)?;
+        let start = desc.nameoff as usize;
+        let end = if index + 1 < total {
+            Self::desc_at(self.data, index + 1)?.nameoff as usize
         } else {
-            let desc = DirentDesc::from(descs[index]);
-            let next_desc = DirentDesc::from(descs[index + 1]);
-            let len = (next_desc.nameoff - desc.nameoff) as usize;
-            Some(Dirent {
-                desc,
-                name: &self.data[desc.nameoff as usize..(desc.nameoff as usize) + len],
-            })
+            self.data.len()
+        };
+        // Names follow the descriptors and their offsets strictly increase.
+        if start < total * size_of::<DirentDesc>() || start >= end || end > self.data.len() {
+            return Err(EUCLEAN);
+        }
+        let name = trim_dirname(&self.data[start..end]);
+        if name.is_empty() {
+            return Err(EUCLEAN);
         }
+        Ok(Some(Dirent { desc, name }))
+    }
+    /// Binary search the entries of this block, which are sorted by name on disk.
+    /// Returns the index where the name would be inserted if there is no such entry.
+    pub(crate) fn binary_search(&self, name: &[u8]) -> PosixResult<Result<Dirent<'a>, usize>> {
+        let (mut head, mut back) = (0, self.total?);
+        while head < back {
+            let mid = head + (back - head) / 2;
+            let Some(dirent) = self.dirent(mid)? else {
+                break;
+            };
+            match name.cmp(dirent.dirname()) {
+                Ordering::Equal => return Ok(Ok(dirent)),
+                Ordering::Less => back = mid,
+                Ordering::Greater => head = mid + 1,
+            }
+        }
+        Ok(Err(head))
     }
     pub(crate) fn skip_dir(&mut self, offset: usize) {
         self.offset += offset;
     }
-    pub(crate) fn total(&self) -> usize {
+    pub(crate) fn total(&self) -> PosixResult<usize> {
         self.total
     }
 }
 
+/// The last name of a block is padded with NULs up to the end of the block, so a NUL terminates
+/// the on-disk name.
+pub(crate) fn trim_dirname(dirname: &[u8]) -> &[u8] {
+    let len = dirname
+        .iter()
+        .position(|c| *c == 0)
+        .unwrap_or(dirname.len());
+    &dirname[..len]
+}
+
+/// The file type recorded in a directory entry, i.e. one of the `EROFS_FT_*` values.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FileType {
+    /// The type is not recorded in the entry.
+    Unknown,
+    /// Regular
+    Regular,
+    /// Directory
+    Directory,
+    /// CharDevice
+    CharDevice,
+    /// BlockDevice
+    BlockDevice,
+    /// Fifo
+    Fifo,
+    /// Socket
+    Socket,
+    /// Symlink
+    Symlink,
+}
+
+impl TryFrom<u8> for FileType {
+    type Error = Errno;
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            EROFS_FT_UNKNOWN => Ok(Self::Unknown),
+            EROFS_FT_REG_FILE => Ok(Self::Regular),
+            EROFS_FT_DIR => Ok(Self::Directory),
+            EROFS_FT_CHRDEV => Ok(Self::CharDevice),
+            EROFS_FT_BLKDEV => Ok(Self::BlockDevice),
+            EROFS_FT_FIFO => Ok(Self::Fifo),
+            EROFS_FT_SOCK => Ok(Self::Socket),
+            EROFS_FT_SYMLINK => Ok(Self::Symlink),
+            _ => Err(EUCLEAN),
+        }
+    }
+}
+
+impl FileType {
+    /// The type of the inode an entry of this type refers to, or None if it is unknown.
+    pub fn inode_type(&self) -> Option<Type> {
+        match self {
+            Self::Unknown => None,
+            Self::Regular => Some(Type::Regular),
+            Self::Directory => Some(Type::Directory),
+            Self::CharDevice => Some(Type::Character),
+            Self::BlockDevice => Some(Type::Block),
+            Self::Fifo => Some(Type::Fifo),
+            Self::Socket => Some(Type::Socket),
+            Self::Symlink => Some(Type::Link),
+        }
+    }
+
+    /// Check that the entry agrees with the inode it refers to.
+    /// Entries of unknown type are consistent with any inode.
+    pub fn check(&self, info: &InodeInfo) -> PosixResult<()> {
+        match self.inode_type() {
+            Some(ty) if ty != info.inode_type() => Err(EUCLEAN),
+            _ => Ok(()),
+        }
+    }
+}
+
 impl<'a> Iterator for DirCollection<'a> {
-    type Item = Dirent<'a>;
+    type Item = PosixResult<Dirent<'a>>;
     fn next(&mut self) -> Option<Self::Item> {
-        self.dirent(self.offset).map(|x| {
-            self.offset += 1;
-            x
-        })
+        if self.offset == usize::MAX {
+            return None;
+        }
+        match self.dirent(self.offset) {
+            Ok(dirent) => {
+                self.offset += 1;
+                dirent.map(Ok)
+            }
+            Err(e) => {
+                self.offset = usize::MAX;
+                Some(Err(e))
+            }
+        }
     }
 }
 
 impl<'a> Dirent<'a> {
-    /// Dirname
+    /// Dirname, without the NUL padding of the last entry in a block.
     pub fn dirname(&self) -> &'a [u8] {
         self.name
     }
@@ -107,4 +240,204 @@ impl<'a> Dirent<'a> {
     pub fn desc(&self) -> &DirentDesc {
         &self.desc
     }
+    /// The typed file type of the entry. Returns EUCLEAN if the on-disk value is invalid.
+    pub fn file_type(&self) -> PosixResult<FileType> {
+        FileType::try_from(self.desc.file_type)
+    }
+}
+
+/// An owned directory entry yielded by [`ReadDir`].
+#[derive(Debug)]
+pub struct DirEntry {
+    name: Vec<u8>,
+    nid: Nid,
+    file_type: FileType,
+    offset: Off,
+}
+
+impl DirEntry {
+    /// The name of the entry without any trailing padding.
+    pub fn name(&self) -> &[u8] {
+        &self.name
+    }
+    /// The nid of the inode the entry refers to.
+    pub fn nid(&self) -> Nid {
+        self.nid
+    }
+    /// The file type recorded in the entry.
+    pub fn file_type(&self) -> FileType {
+        self.file_type
+    }
+    /// The position right after this entry. Passing it to [`ReadDir::seek`] resumes the
+    /// iteration at the next entry, much like `d_off` of `getdents`.
+    pub fn offset(&self) -> Off {
+        self.offset
+    }
+}
+
+/// An iterator over the entries of a directory, including `.` and `..`, in on-disk order.
+/// Each block is read once and only while its entries are being yielded.
+pub struct ReadDir<'a, I>
+where
+    I: Inode,
+{
+    fs: &'a dyn FileSystem<I>,
+    inode: &'a I,
+    pos: Off,
+    block: Option<BlockBuffer<'a>>,
+    verify: bool,
+}
+
+impl<'a, I> ReadDir<'a, I>
+where
+    I: Inode,
+{
+    pub(crate) fn new(fs: &'a dyn FileSystem<I>, inode: &'a I) -> Self {
+        Self {
+            fs,
+            inode,
+            pos: 0,
+            block: None,
+            verify: false,
+        }
+    }
+
+    /// Read the inode of every entry and check that it has the type recorded in the entry.
+    /// Mismatches are reported as EUCLEAN. This costs one inode read per entry.
+    pub fn verify_file_types(mut self, verify: bool) -> Self {
+        self.verify = verify;
+        self
+    }
+
+    /// Move to the position returned by [`DirEntry::offset`] of a previously yielded entry.
+    pub fn seek(&mut self, offset: Off) {
+        self.pos = offset;
+        self.block = None;
+    }
+
+    fn load_block(&mut self, blkstart: Off) -> PosixResult<&dyn Buffer> {
+        let block = match self.block.take() {
+            Some(block) => block,
+            None => self.fs.dir_block(self.inode, blkstart)?,
+        };
+        Ok(self.block.insert(block))
+    }
+
+    fn try_next(&mut self) -> PosixResult<Option<DirEntry>> {
+        let (fs, verify) = (self.fs, self.verify);
+        let (dirblksz, file_size) = (fs.superblock().dirblksz(), self.inode.info().file_size());
+        let unit = size_of::<DirentDesc>() as Off;
+        while self.pos < file_size {
+            let blkstart = round!(DOWN, self.pos, dirblksz);
+            let index = round!(UP, self.pos - blkstart, unit) / unit;
+            let collection = self.load_block(blkstart)?.iter_dir();
+            let dirent = match collection.dirent(index as usize)? {
+                Some(dirent) => dirent,
+                None => {
+                    self.seek(blkstart + dirblksz);
+                    continue;
+                }
+            };
+            let file_type = dirent.file_type()?;
+            if verify {
+                file_type.check(&fs.read_inode_info(dirent.desc.nid)?)?;
+            }
+            let mut name = Vec::new();
+            extend_from_slice(&mut name, dirent.dirname())?;
+            let entry = DirEntry {
+                name,
+                nid: dirent.desc.nid,
+                file_type,
+                offset: if index as usize + 1 < collection.total()? {
+                    blkstart + (index + 1) * unit
+                } else {
+                    blkstart + dirblksz
+                },
+            };
+            if entry.offset - blkstart == dirblksz {
+                self.seek(entry.offset);
+            } else {
+                self.pos = entry.offset;
+            }
+            return Ok(Some(entry));
+        }
+        Ok(None)
+    }
+}
+
+impl<'a, I> Iterator for ReadDir<'a, I>
+where
+    I: Inode,
+{
+    type Item = PosixResult<DirEntry>;
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.try_next() {
+            Ok(entry) => entry.map(Ok),
+            Err(e) => {
+                // Stop after the first error instead of yielding it over and over again.
+                self.pos = Off::MAX;
+                self.block = None;
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encode_block(entries: &[(u16, &[u8])], size: usize) -> Vec<u8> {
+        let mut block = Vec::new();
+        for (nid, (nameoff, _)) in entries.iter().enumerate() {
+            block.extend_from_slice(&(nid as u64).to_le_bytes());
+            block.extend_from_slice(&nameoff.to_le_bytes());
+            block.extend_from_slice(&[EROFS_FT_REG_FILE, 0]);
+        }
+        for (_, name) in entries {
+            block.extend_from_slice(name);
+        }
+        block.resize(size, 0);
+        block
+    }
+
+    fn collect(block: &[u8]) -> Vec<PosixResult<Vec<u8>>> {
+        DirCollection::new(block)
+            .map(|d| d.map(|d| d.dirname().to_vec()))
+            .collect()
+    }
+
+    #[test]
+    fn test_dir_collection() {
+        let block = encode_block(&[(24, b"a"), (25, b"bc")], 64);
+        assert_eq!(collect(&block), [Ok(b"a".to_vec()), Ok(b"bc".to_vec())]);
+    }
+
+    #[test]
+    fn test_dir_collection_corrupted() {
+        // Too short to hold a single descriptor.
+        assert_eq!(collect(&[0u8; 8]), [Err(EUCLEAN)]);
+        // nameoff of the first entry is not a multiple of the descriptor size.
+        assert_eq!(collect(&encode_block(&[(13, b"a")], 32)), [Err(EUCLEAN)]);
+        // The descriptors don't fit the block.
+        assert_eq!(collect(&encode_block(&[(48, b"a")], 32)), [Err(EUCLEAN)]);
+        // Name offsets going backwards or beyond the block.
+        assert_eq!(
+            collect(&encode_block(&[(24, b"a"), (20, b"b")], 64)),
+            [Err(EUCLEAN)]
+        );
+        assert_eq!(
+            collect(&encode_block(&[(24, b"a"), (80, b"b")], 64)),
+            [Err(EUCLEAN)]
+        );
+        // Empty names.
+        assert_eq!(
+            collect(&encode_block(&[(24, b"a"), (25, b"")], 32)),
+            [Ok(b"a".to_vec()), Err(EUCLEAN)]
+        );
+        assert_eq!(
+            collect(&encode_block(&[(24, b"\0a"), (26, b"b")], 32)),
+            [Err(EUCLEAN)]
+        );
+    }
 }
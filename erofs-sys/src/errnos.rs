@@ -276,17 +276,46 @@ pub enum Errno {
 }
 
 impl From<i32> for Errno {
+    /// Accepts both the positive errno values of userspace and the negative ones of the kernel.
     fn from(value: i32) -> Self {
-        if (-value) <= 0 || (-value) > Errno::EUNKNOWN as i32 {
-            Errno::EUNKNOWN
-        } else {
-            // Safety: The value is guaranteed to be a valid errno and the memory
-            // layout is the same for both types.
-            unsafe { core::mem::transmute::<i32, Errno>(value) }
+        match value.unsigned_abs() {
+            // 41 and 58 are unassigned on Linux, where EWOULDBLOCK and EDEADLOCK share the values
+            // of EAGAIN and EDEADLK, so they have no variant.
+            41 | 58 => EUNKNOWN,
+            value if value == 0 || value >= Errno::EUNKNOWN as u32 => EUNKNOWN,
+            // Safety: Every value between NONE and EUNKNOWN except the gaps above is a
+            // variant and the memory layout is the same for both types.
+            value => unsafe { core::mem::transmute::<i32, Errno>(value as i32) },
         }
     }
 }
 
+#[cfg(any(feature = "std", test))]
+impl From<std::io::Error> for Errno {
+    fn from(value: std::io::Error) -> Self {
+        use std::io::ErrorKind;
+        if let Some(errno) = value.raw_os_error() {
+            return errno.into();
+        }
+        match value.kind() {
+            ErrorKind::NotFound => ENOENT,
+            ErrorKind::PermissionDenied => EACCES,
+            ErrorKind::InvalidInput => EINVAL,
+            ErrorKind::Interrupted => EINTR,
+            ErrorKind::OutOfMemory => ENOMEM,
+            ErrorKind::Unsupported => EOPNOTSUPP,
+            _ => EIO,
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl From<Errno> for std::io::Error {
+    fn from(value: Errno) -> Self {
+        std::io::Error::from_raw_os_error(value as i32)
+    }
+}
+
 pub(crate) use Errno::*;
 
 #[cfg(test)]
@@ -298,4 +327,27 @@ mod tests {
         assert_eq!(Errno::ERANGE as i32, 34);
         assert_eq!(Errno::ENODATA as i32, 61);
     }
+
+    #[test]
+    fn test_errno_from_i32() {
+        assert_eq!(Errno::from(34), ERANGE);
+        assert_eq!(Errno::from(-34), ERANGE);
+        assert_eq!(Errno::from(11), EAGAIN);
+        assert_eq!(Errno::from(-35), EDEADLK);
+        assert_eq!(Errno::from(41), EUNKNOWN);
+        assert_eq!(Errno::from(-58), EUNKNOWN);
+        assert_eq!(Errno::from(EHWPOISON as i32), EHWPOISON);
+        assert_eq!(Errno::from(0), EUNKNOWN);
+        assert_eq!(Errno::from(EUNKNOWN as i32), EUNKNOWN);
+        assert_eq!(Errno::from(i32::MIN), EUNKNOWN);
+    }
+
+    #[test]
+    fn test_errno_from_io_error() {
+        extern crate std;
+        use std::io::{Error, ErrorKind};
+        assert_eq!(Errno::from(Error::from_raw_os_error(5)), EIO);
+        assert_eq!(Errno::from(Error::from(ErrorKind::NotFound)), ENOENT);
+        assert_eq!(Errno::from(Error::from(ErrorKind::UnexpectedEof)), EIO);
+    }
 }
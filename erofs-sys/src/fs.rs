@@ -0,0 +1,475 @@
+// Copyright 2024 Yiyang Wu
+// SPDX-License-Identifier: MIT or GPL-2.0-or-later
+
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use std::collections::{hash_map::Entry, HashMap};
+use std::ffi::{OsStr, OsString};
+use std::io::{self, Read};
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, PoisonError};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use super::data::backends::uncompressed::*;
+use super::dir::DirEntry;
+use super::errnos::*;
+use super::file::{ImageFileSystem, EROFS_MAX_IO_LEN};
+use super::inode::*;
+use super::io::File;
+use super::operations::*;
+use super::path::*;
+use super::superblock::*;
+use super::walk::{self, WalkControl, WalkOrder};
+use super::xattrs::*;
+use super::*;
+
+#[derive(Clone)]
+struct CachedInode {
+    info: InodeInfo,
+    xattrs_shared_entries: XAttrSharedEntries,
+    nid: Nid,
+}
+
+impl Inode for CachedInode {
+    fn new(
+        _sb: &SuperBlock,
+        info: InodeInfo,
+        nid: Nid,
+        xattrs_shared_entries: XAttrSharedEntries,
+    ) -> Self {
+        Self {
+            info,
+            xattrs_shared_entries,
+            nid,
+        }
+    }
+    fn info(&self) -> &InodeInfo {
+        &self.info
+    }
+    fn xattrs_shared_entries(&self) -> &XAttrSharedEntries {
+        &self.xattrs_shared_entries
+    }
+    fn nid(&self) -> Nid {
+        self.nid
+    }
+}
+
+struct InodeCache(HashMap<Nid, CachedInode>);
+
+impl InodeCollection for InodeCache {
+    type I = CachedInode;
+    fn iget(&mut self, nid: Nid, f: &dyn FileSystem<Self::I>) -> PosixResult<&mut Self::I> {
+        match self.0.entry(nid) {
+            Entry::Vacant(v) => {
+                let info = f.read_inode_info(nid)?;
+                let xattrs_header = f.read_inode_xattrs_shared_entries(nid, &info)?;
+                Ok(v.insert(Self::I::new(f.superblock(), info, nid, xattrs_header)))
+            }
+            Entry::Occupied(o) => Ok(o.into_mut()),
+        }
+    }
+    fn release(&mut self, nid: Nid) {
+        self.0.remove(&nid);
+    }
+}
+
+/// Metadata of an inode, the counterpart of [`std::fs::Metadata`].
+#[derive(Debug, Clone, Copy)]
+pub struct Metadata {
+    nid: Nid,
+    file_type: Type,
+    len: u64,
+    permissions: u16,
+    uid: u32,
+    gid: u32,
+    nlink: u32,
+    modified: SystemTime,
+}
+
+impl Metadata {
+    fn new(sb: &SuperBlock, inode: &CachedInode) -> Self {
+        let info = inode.info();
+        let (nlink, secs, nsecs) = match info {
+            InodeInfo::Extended(e) => (e.i_nlink, e.i_mtime as i64, e.i_mtime_nsec),
+            // Compact inodes don't record a timestamp and share the build time of the image.
+            InodeInfo::Compact(c) => (c.i_nlink as u32, sb.build_time, sb.build_time_nsec as u32),
+        };
+        let modified = if secs >= 0 {
+            UNIX_EPOCH + Duration::new(secs as u64, nsecs)
+        } else {
+            UNIX_EPOCH - Duration::new(secs.unsigned_abs(), 0) + Duration::from_nanos(nsecs.into())
+        };
+        Self {
+            nid: inode.nid(),
+            file_type: info.inode_type(),
+            len: info.file_size(),
+            permissions: info.inode_perm(),
+            uid: info.uid(),
+            gid: info.gid(),
+            nlink,
+            modified,
+        }
+    }
+    /// The nid of the inode, which is unique within the image.
+    pub fn nid(&self) -> Nid {
+        self.nid
+    }
+    /// The type of the inode.
+    pub fn file_type(&self) -> Type {
+        self.file_type
+    }
+    /// Whether this is a directory.
+    pub fn is_dir(&self) -> bool {
+        self.file_type == Type::Directory
+    }
+    /// Whether this is a regular file.
+    pub fn is_file(&self) -> bool {
+        self.file_type == Type::Regular
+    }
+    /// Whether this is a symbolic link.
+    pub fn is_symlink(&self) -> bool {
+        self.file_type == Type::Link
+    }
+    /// The size of the file data in bytes.
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+    /// Whether the file data is empty.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+    /// The permission bits, e.g. `0o755`.
+    pub fn permissions(&self) -> u16 {
+        self.permissions
+    }
+    /// The owner.
+    pub fn uid(&self) -> u32 {
+        self.uid
+    }
+    /// The group.
+    pub fn gid(&self) -> u32 {
+        self.gid
+    }
+    /// The number of hard links.
+    pub fn nlink(&self) -> u32 {
+        self.nlink
+    }
+    /// The modification time.
+    pub fn modified(&self) -> SystemTime {
+        self.modified
+    }
+}
+
+/// An entry visited by [`Erofs::walk`], the [`walk::WalkEntry`] of the facade with a
+/// [`Path`] and [`Metadata`] in place of raw bytes and inodes.
+pub struct WalkEntry<'a> {
+    entry: &'a walk::WalkEntry<'a, CachedInode>,
+    metadata: Metadata,
+}
+
+impl WalkEntry<'_> {
+    /// The absolute path of the entry within the image.
+    pub fn path(&self) -> &Path {
+        Path::new(OsStr::from_bytes(self.entry.path()))
+    }
+    /// The metadata of the entry, symbolic links are not followed.
+    pub fn metadata(&self) -> &Metadata {
+        &self.metadata
+    }
+    /// The nid of the directory holding the entry, the root is its own parent.
+    pub fn parent(&self) -> Nid {
+        self.entry.parent()
+    }
+    /// The number of components between the root and the entry.
+    pub fn depth(&self) -> usize {
+        self.entry.depth()
+    }
+    /// Whether the same inode has already been visited under another path.
+    pub fn is_hardlink(&self) -> bool {
+        self.entry.is_hardlink()
+    }
+}
+
+/// A mounted EROFS image with the usual `std::fs` style accessors. Paths are resolved from the
+/// root of the image, inodes are cached internally and every error is an [`io::Error`].
+pub struct Erofs {
+    fs: Box<dyn FileSystem<CachedInode> + Send + Sync>,
+    inodes: Mutex<InodeCache>,
+}
+
+impl Erofs {
+    fn new(fs: Box<dyn FileSystem<CachedInode> + Send + Sync>) -> Self {
+        Self {
+            fs,
+            inodes: Mutex::new(InodeCache(HashMap::new())),
+        }
+    }
+
+    /// Open the image stored in the file at `path`.
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        let file = std::fs::File::open(path)?;
+        let fs = ImageFileSystem::try_new(UncompressedBackend::new(file))?;
+        Ok(Self::new(Box::new(fs)))
+    }
+
+    /// Open an image held in memory.
+    pub fn from_bytes(image: impl Into<Vec<u8>>) -> io::Result<Self> {
+        let fs = ImageFileSystem::try_new(UncompressedBackend::<Vec<u8>>::new(image.into()))?;
+        Ok(Self::new(Box::new(fs)))
+    }
+
+    /// The superblock of the image.
+    pub fn superblock(&self) -> &SuperBlock {
+        self.fs.superblock()
+    }
+
+    fn inode(&self, path: &Path, follow: bool) -> io::Result<CachedInode> {
+        let fs: &dyn FileSystem<CachedInode> = &*self.fs;
+        let mut inodes = self.inodes.lock().unwrap_or_else(PoisonError::into_inner);
+        let options = ResolveOptions::new().follow_symlinks(follow);
+        let root = fs.superblock().root_nid as Nid;
+        let resolved = resolve(
+            fs,
+            &mut *inodes,
+            root,
+            path.as_os_str().as_encoded_bytes(),
+            options,
+        )?;
+        Ok(inodes.iget(resolved.nid(), fs)?.clone())
+    }
+
+    /// Query the metadata of `path`, following symbolic links.
+    pub fn metadata(&self, path: impl AsRef<Path>) -> io::Result<Metadata> {
+        let inode = self.inode(path.as_ref(), true)?;
+        Ok(Metadata::new(self.superblock(), &inode))
+    }
+
+    /// Query the metadata of `path` without following a final symbolic link.
+    pub fn symlink_metadata(&self, path: impl AsRef<Path>) -> io::Result<Metadata> {
+        let inode = self.inode(path.as_ref(), false)?;
+        Ok(Metadata::new(self.superblock(), &inode))
+    }
+
+    /// List the directory at `path`, without `.` and `..`.
+    pub fn read_dir(&self, path: impl AsRef<Path>) -> io::Result<Vec<DirEntry>> {
+        let inode = self.inode(path.as_ref(), true)?;
+        let mut entries = Vec::new();
+        for entry in read_dir(&*self.fs, &inode)? {
+            let entry = entry?;
+            if entry.name() != b"." && entry.name() != b".." {
+                entries.push(entry);
+            }
+        }
+        Ok(entries)
+    }
+
+    /// Read the whole content of the file at `path`.
+    pub fn read(&self, path: impl AsRef<Path>) -> io::Result<Vec<u8>> {
+        let inode = self.inode(path.as_ref(), true)?;
+        if inode.info().inode_type() == Type::Directory {
+            return Err(EISDIR.into());
+        }
+        // The size comes from the image, so only trust it up to a bound and let the buffer grow
+        // with the data actually read.
+        let size = inode.info().file_size().min(EROFS_MAX_IO_LEN);
+        let mut content = Vec::new();
+        content
+            .try_reserve_exact(size as usize)
+            .map_err(|_| io::Error::from(io::ErrorKind::OutOfMemory))?;
+        File::new(&*self.fs, &inode).read_to_end(&mut content)?;
+        Ok(content)
+    }
+
+    /// Read the target of the symbolic link at `path`.
+    pub fn read_link(&self, path: impl AsRef<Path>) -> io::Result<PathBuf> {
+        let inode = self.inode(path.as_ref(), false)?;
+        let target = readlink(&*self.fs, &inode)?;
+        Ok(PathBuf::from(OsString::from_vec(target)))
+    }
+
+    /// Read every extended attribute of `path`, following symbolic links.
+    pub fn xattrs(&self, path: impl AsRef<Path>) -> io::Result<Vec<XAttrEntry>> {
+        let inode = self.inode(path.as_ref(), true)?;
+        let entries = xattrs(&*self.fs, &inode)?.collect::<PosixResult<_>>()?;
+        Ok(entries)
+    }
+
+    /// Visit every inode of the image in `order`, starting with the root directory. The visitor
+    /// decides whether to descend into directories or to stop, and its errors end the walk.
+    pub fn walk(
+        &self,
+        order: WalkOrder,
+        mut visitor: impl FnMut(&WalkEntry<'_>) -> io::Result<WalkControl>,
+    ) -> io::Result<()> {
+        let sb = self.superblock();
+        // Errors of the visitor don't have to be errnos, so they are carried around the walk.
+        let mut failure = None;
+        let result = walk(&*self.fs, sb.root_nid as Nid, order, &mut |entry| {
+            let entry = WalkEntry {
+                entry,
+                metadata: Metadata::new(sb, entry.inode()),
+            };
+            visitor(&entry).or_else(|e| {
+                failure = Some(e);
+                Ok(WalkControl::Stop)
+            })
+        });
+        match failure {
+            Some(e) => Err(e),
+            None => Ok(result?),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::superblock::tests::*;
+    use super::*;
+    use std::io::Read;
+    use std::string::String;
+    use std::vec;
+
+    fn test_erofs(erofs: &Erofs, xattrs: bool) {
+        let root = erofs.metadata("/").unwrap();
+        assert!(root.is_dir());
+        assert_eq!(root.nid(), erofs.superblock().root_nid as Nid);
+
+        let listed = erofs.read_dir("/").unwrap();
+        for name in [
+            &b"README.md"[..],
+            b"blob.jpg",
+            b"images",
+            b"lipsum.txt",
+            b"texts",
+        ] {
+            assert!(listed.iter().any(|e| e.name() == name));
+        }
+        assert!(!listed.iter().any(|e| e.name() == b"." || e.name() == b".."));
+
+        let lipsum = erofs.read("texts/lipsum.txt").unwrap();
+        assert_eq!(lipsum.len(), 5060);
+        assert_eq!(erofs.read("/lipsum.txt").unwrap(), lipsum);
+        assert_eq!(erofs.metadata("/lipsum.txt").unwrap().len(), 5060);
+        let link = erofs.symlink_metadata("/lipsum.txt").unwrap();
+        assert!(link.is_symlink());
+        assert_eq!(
+            erofs.read_link("/lipsum.txt").unwrap(),
+            Path::new("texts/lipsum.txt")
+        );
+        assert_eq!(
+            erofs.read_link("/blob.jpg").unwrap(),
+            Path::new("images/inabukumori.jpg")
+        );
+        assert_eq!(erofs.read("/blob.jpg").unwrap().len(), 13735);
+
+        assert_eq!(
+            erofs.metadata("/missing").unwrap_err().kind(),
+            io::ErrorKind::NotFound
+        );
+        assert_eq!(
+            erofs.read("/texts").unwrap_err().raw_os_error(),
+            Some(EISDIR as i32)
+        );
+        assert_eq!(
+            erofs.read_link("/README.md").unwrap_err().raw_os_error(),
+            Some(EINVAL as i32)
+        );
+
+        let xattrs_of_readme = erofs.xattrs("/README.md").unwrap();
+        if xattrs {
+            assert!(xattrs_of_readme
+                .iter()
+                .any(|x| x.full_name() == b"user.sha512sum"));
+        } else {
+            assert!(xattrs_of_readme.is_empty());
+        }
+
+        let mut walked: Vec<String> = Vec::new();
+        erofs
+            .walk(WalkOrder::DepthFirst, |entry| {
+                let metadata = erofs.symlink_metadata(entry.path()).unwrap();
+                assert_eq!(entry.metadata().nid(), metadata.nid());
+                assert_eq!(entry.metadata().is_dir(), metadata.is_dir());
+                walked.push(entry.path().to_str().unwrap().into());
+                Ok(WalkControl::Continue)
+            })
+            .unwrap();
+        let expected = [
+            "/",
+            "/README.md",
+            "/blob.jpg",
+            "/images",
+            "/images/inabukumori.jpg",
+            "/lipsum.txt",
+            "/texts",
+            "/texts/lipsum.txt",
+        ];
+        // Depth-first in directory order, other files of the image may sit in between.
+        let positions: Vec<usize> = expected
+            .iter()
+            .map(|path| walked.iter().position(|p| p == path).unwrap())
+            .collect();
+        assert!(positions.windows(2).all(|w| w[0] < w[1]));
+        assert_eq!(walked.len(), listed.len() + 3);
+
+        // Pruned directories are visited but not descended into, errors of the visitor end it.
+        let mut pruned = Vec::new();
+        erofs
+            .walk(WalkOrder::BreadthFirst, |entry| {
+                pruned.push(entry.path().to_path_buf());
+                Ok(match entry.depth() {
+                    0 => WalkControl::Continue,
+                    _ => WalkControl::Prune,
+                })
+            })
+            .unwrap();
+        assert_eq!(pruned.len(), listed.len() + 1);
+        let error = erofs
+            .walk(WalkOrder::DepthFirst, |entry| match entry.path().to_str() {
+                Some("/texts") => Err(io::Error::other("stop here")),
+                _ => Ok(WalkControl::Continue),
+            })
+            .unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::Other);
+    }
+
+    #[test]
+    fn test_erofs_read_corrupt_size() {
+        let mut testcase = load_fixtures_full().next().unwrap();
+        let mut image = Vec::new();
+        testcase.file.read_to_end(&mut image).unwrap();
+        let erofs = Erofs::from_bytes(image.clone()).unwrap();
+        let nid = erofs.metadata("/texts/lipsum.txt").unwrap().nid();
+        let pos = erofs.superblock().iloc(nid) as usize;
+        // Claim a size far beyond the image, the read must fail instead of trying to allocate all
+        // of it upfront.
+        if image[pos] & 1 == 0 {
+            image[pos + 8..pos + 12].copy_from_slice(&u32::MAX.to_le_bytes());
+        } else {
+            image[pos + 8..pos + 16].copy_from_slice(&(1u64 << 40).to_le_bytes());
+        }
+        let erofs = Erofs::from_bytes(image).unwrap();
+        assert!(erofs.metadata("/texts/lipsum.txt").unwrap().len() >= u32::MAX as u64);
+        assert!(erofs.read("/texts/lipsum.txt").is_err());
+    }
+
+    #[test]
+    fn test_erofs_facade() {
+        for testcase in load_fixtures_full().chain(load_fixtures_noxattr()) {
+            let mut image = Vec::new();
+            (&testcase.file).read_to_end(&mut image).unwrap();
+            test_erofs(&Erofs::from_bytes(image).unwrap(), testcase.xattrs);
+        }
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/sample_4096.img");
+        test_erofs(&Erofs::open(path).unwrap(), true);
+
+        assert_eq!(
+            Erofs::from_bytes(vec![0u8; 4096])
+                .err()
+                .unwrap()
+                .raw_os_error(),
+            Some(EINVAL as i32)
+        );
+    }
+}
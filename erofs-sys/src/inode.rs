@@ -317,6 +317,22 @@ impl InodeInfo {
         mode & 0o777
     }
 
+    /// uid
+    pub fn uid(&self) -> u32 {
+        match self {
+            Self::Extended(extended) => extended.i_uid,
+            Self::Compact(compact) => compact.i_uid as u32,
+        }
+    }
+
+    /// gid
+    pub fn gid(&self) -> u32 {
+        match self {
+            Self::Extended(extended) => extended.i_gid,
+            Self::Compact(compact) => compact.i_gid as u32,
+        }
+    }
+
     pub(crate) fn xattr_size(&self) -> Off {
         match self {
             Self::Extended(extended) => {
@@ -340,6 +356,9 @@ impl InodeInfo {
     }
 }
 
+/// Nids are limited so that the inode location `meta_blkaddr + nid * 32` always fits an Off.
+pub(crate) const EROFS_NID_BITS: u32 = 58;
+
 pub(crate) type CompactInodeInfoBuf = [u8; size_of::<CompactInodeInfo>()];
 pub(crate) type ExtendedInodeInfoBuf = [u8; size_of::<ExtendedInodeInfo>()];
 pub(crate) const DEFAULT_INODE_BUF: ExtendedInodeInfoBuf = [0; size_of::<ExtendedInodeInfo>()];
@@ -361,6 +380,38 @@ pub trait Inode: Sized {
     fn nid(&self) -> Nid;
 }
 
+/// A bare inode used internally to reach metadata stored in the data of special inodes, e.g. the
+/// long xattr prefixes inside the packed inode, before any user inode type is involved.
+pub(crate) struct MetaInode {
+    info: InodeInfo,
+    xattrs_shared_entries: XAttrSharedEntries,
+    nid: Nid,
+}
+
+impl Inode for MetaInode {
+    fn new(
+        _sb: &SuperBlock,
+        info: InodeInfo,
+        nid: Nid,
+        xattrs_shared_entries: XAttrSharedEntries,
+    ) -> Self {
+        Self {
+            info,
+            xattrs_shared_entries,
+            nid,
+        }
+    }
+    fn info(&self) -> &InodeInfo {
+        &self.info
+    }
+    fn xattrs_shared_entries(&self) -> &XAttrSharedEntries {
+        &self.xattrs_shared_entries
+    }
+    fn nid(&self) -> Nid {
+        self.nid
+    }
+}
+
 /// Represents the error which occurs when trying to convert the inode.
 #[derive(Debug)]
 pub enum InodeError {
@@ -404,13 +455,17 @@ where
         let f = value.0;
         let sb = f.superblock();
         let nid = value.1;
+        // Keep the inode location and everything derived from it far away from overflowing.
+        if nid >> EROFS_NID_BITS != 0 {
+            return Err(Errno::EUCLEAN);
+        }
         let offset = sb.iloc(nid);
         let accessor = sb.blk_access(offset);
         let mut buf: ExtendedInodeInfoBuf = DEFAULT_INODE_BUF;
         f.backend().fill(&mut buf[0..32], 0, offset)?;
         let compact_buf: CompactInodeInfoBuf = buf[0..32].try_into().unwrap();
         let r: Result<CompactInodeInfo, InodeError> = CompactInodeInfo::try_from(compact_buf);
-        match r {
+        let info = match r {
             Ok(compact) => Ok(InodeInfo::Compact(compact)),
             Err(e) => match e {
                 InodeError::VersionError => {
@@ -450,7 +505,12 @@ where
                 }
                 InodeError::PosixError(e) => Err(e),
             },
+        }?;
+        // Files can't have more blocks than a block address can express.
+        if info.file_size() > sb.blkpos(Blk::MAX) {
+            return Err(Errno::EUCLEAN);
         }
+        Ok(info)
     }
 }
 
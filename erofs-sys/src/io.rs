@@ -0,0 +1,219 @@
+// Copyright 2024 Yiyang Wu
+// SPDX-License-Identifier: MIT or GPL-2.0-or-later
+
+use alloc::vec::Vec;
+use std::io::{self, BufRead, Read, Seek, SeekFrom};
+
+use super::inode::*;
+use super::superblock::*;
+use super::*;
+
+/// A read-only file handle over a regular file, implementing [`Read`], [`Seek`] and [`BufRead`].
+/// Every read maps the current position directly, so sequential and random access cost the same.
+pub struct File<'a, I>
+where
+    I: Inode,
+{
+    fs: &'a dyn FileSystem<I>,
+    inode: &'a I,
+    pos: Off,
+    // The block holding `pos`, filled on demand by BufRead::fill_buf.
+    buf: Vec<u8>,
+    buf_start: Off,
+    buf_len: usize,
+}
+
+impl<'a, I> File<'a, I>
+where
+    I: Inode,
+{
+    /// Open the file data of `inode`, positioned at the start.
+    pub fn new(fs: &'a dyn FileSystem<I>, inode: &'a I) -> Self {
+        Self {
+            fs,
+            inode,
+            pos: 0,
+            buf: Vec::new(),
+            buf_start: 0,
+            buf_len: 0,
+        }
+    }
+
+    /// The size of the file in bytes.
+    pub fn len(&self) -> Off {
+        self.inode.info().file_size()
+    }
+
+    /// Whether the file is empty.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The inode being read.
+    pub fn inode(&self) -> &'a I {
+        self.inode
+    }
+
+    fn buffered(&self) -> &[u8] {
+        if self.pos < self.buf_start || self.pos >= self.buf_start + self.buf_len as Off {
+            return &[];
+        }
+        &self.buf[(self.pos - self.buf_start) as usize..self.buf_len]
+    }
+}
+
+impl<I> Read for File<'_, I>
+where
+    I: Inode,
+{
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        let blksz = self.fs.superblock().blksz() as usize;
+        // Large reads bypass the buffer altogether.
+        if self.buffered().is_empty() && out.len() >= blksz {
+            let len = self.fs.read_at(self.inode, self.pos, out)?;
+            self.pos += len as Off;
+            return Ok(len);
+        }
+        let data = self.fill_buf()?;
+        let len = data.len().min(out.len());
+        out[..len].copy_from_slice(&data[..len]);
+        self.consume(len);
+        Ok(len)
+    }
+}
+
+impl<I> BufRead for File<'_, I>
+where
+    I: Inode,
+{
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        if self.buffered().is_empty() && self.pos < self.len() {
+            let blksz = self.fs.superblock().blksz();
+            self.buf.resize(blksz as usize, 0);
+            // Align to blocks so that a buffer never spans two of them.
+            let start = self.pos - self.pos % blksz;
+            self.buf_len = 0;
+            self.buf_len = self.fs.read_at(self.inode, start, &mut self.buf)?;
+            self.buf_start = start;
+        }
+        Ok(self.buffered())
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.pos += amt.min(self.buffered().len()) as Off;
+    }
+}
+
+impl<I> Seek for File<'_, I>
+where
+    I: Inode,
+{
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let (base, delta) = match pos {
+            SeekFrom::Start(offset) => {
+                self.pos = offset;
+                return Ok(offset);
+            }
+            SeekFrom::End(delta) => (self.len(), delta),
+            SeekFrom::Current(delta) => (self.pos, delta),
+        };
+        match base.checked_add_signed(delta) {
+            Some(pos) => {
+                self.pos = pos;
+                Ok(pos)
+            }
+            None => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "invalid seek to a negative or overflowing position",
+            )),
+        }
+    }
+
+    fn stream_position(&mut self) -> io::Result<u64> {
+        Ok(self.pos)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::data::backends::uncompressed::*;
+    use super::super::file::ImageFileSystem;
+    use super::super::operations::*;
+    use super::super::superblock::tests::*;
+    use super::*;
+    use alloc::boxed::Box;
+    use std::collections::HashMap;
+    use std::string::String;
+    use std::vec;
+
+    fn test_file(sbi: &mut SimpleBufferedFileSystem) {
+        let root = sbi.filesystem.superblock().root_nid as Nid;
+        let inode = lookup(
+            &*sbi.filesystem,
+            &mut sbi.inodes,
+            root,
+            b"/texts/lipsum.txt",
+        )
+        .unwrap();
+        let size = inode.info().file_size() as usize;
+        let mut expected = vec![0u8; size];
+        sbi.filesystem.read_at(inode, 0, &mut expected).unwrap();
+
+        let mut file = File::new(&*sbi.filesystem, inode);
+        let mut content = Vec::new();
+        file.read_to_end(&mut content).unwrap();
+        assert_eq!(content, expected);
+        assert_eq!(file.read(&mut [0u8; 16]).unwrap(), 0);
+
+        // Small reads go through the block buffer.
+        file.rewind().unwrap();
+        let mut content = Vec::new();
+        let mut chunk = [0u8; 7];
+        loop {
+            let len = file.read(&mut chunk).unwrap();
+            if len == 0 {
+                break;
+            }
+            content.extend_from_slice(&chunk[..len]);
+        }
+        assert_eq!(content, expected);
+
+        assert_eq!(file.seek(SeekFrom::End(-10)).unwrap(), size as u64 - 10);
+        let mut tail = Vec::new();
+        file.read_to_end(&mut tail).unwrap();
+        assert_eq!(tail, expected[size - 10..]);
+        assert_eq!(file.seek(SeekFrom::Current(-20)).unwrap(), size as u64 - 20);
+        assert_eq!(file.seek(SeekFrom::Start(4000)).unwrap(), 4000);
+        let mut middle = [0u8; 200];
+        file.read_exact(&mut middle).unwrap();
+        assert_eq!(middle[..], expected[4000..4200]);
+        assert!(file.seek(SeekFrom::Current(-5000)).is_err());
+        assert_eq!(file.seek(SeekFrom::End(100)).unwrap(), size as u64 + 100);
+        assert_eq!(file.read(&mut middle).unwrap(), 0);
+
+        file.rewind().unwrap();
+        let mut lines = String::new();
+        for line in file.lines() {
+            lines.push_str(&line.unwrap());
+            lines.push('\n');
+        }
+        assert_eq!(
+            lines.trim_end(),
+            String::from_utf8(expected).unwrap().trim_end()
+        );
+    }
+
+    #[test]
+    fn test_file_read_seek() {
+        for testcase in load_fixtures_full().chain(load_fixtures_noxattr()) {
+            let mut sbi: SimpleBufferedFileSystem = SuperblockInfo::new(
+                Box::new(
+                    ImageFileSystem::try_new(UncompressedBackend::new(testcase.file)).unwrap(),
+                ),
+                HashMap::new(),
+                (),
+            );
+            test_file(&mut sbi);
+        }
+    }
+}
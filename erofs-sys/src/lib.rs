@@ -11,6 +11,8 @@
 
 #[cfg(not(CONFIG_EROFS_FS = "y"))]
 extern crate alloc;
+#[cfg(any(feature = "std", test))]
+extern crate std;
 
 /// Erofs requires block index to a 32 bit unsigned integer.
 pub type Blk = u32;
@@ -20,8 +22,11 @@ pub type Off = u64;
 pub type Nid = u64;
 
 pub(crate) const EROFS_SUPER_OFFSET: Off = 1024;
+/// The maximum length of a file name or an xattr name suffix.
+pub(crate) const EROFS_NAME_LEN: usize = 255;
 
 pub(crate) mod alloc_helper;
+pub(crate) mod cache;
 pub(crate) mod compression;
 /// Data Module
 pub mod data;
@@ -30,15 +35,26 @@ pub(crate) mod devices;
 pub mod dir;
 /// Errno Module
 pub mod errnos;
+/// High level std::fs style API
+#[cfg(feature = "std")]
+pub mod fs;
 /// Inode Module
 pub mod inode;
+/// std::io adaptors
+#[cfg(feature = "std")]
+pub mod io;
 pub(crate) mod map;
 /// Operations Modules
 pub mod operations;
+/// Path Resolution Module
+pub mod path;
 /// Superblock
 pub mod superblock;
+/// Tree Walking Module
+pub mod walk;
 /// Xattrs Module
 pub mod xattrs;
+pub(crate) mod xxhash;
 pub(crate) use errnos::Errno;
 /// Documentation
 pub type PosixResult<T> = Result<T, errnos::Errno>;
@@ -55,3 +71,6 @@ macro_rules! round {
 }
 
 pub use superblock::{file, mem};
+
+#[cfg(feature = "std")]
+pub use fs::Erofs;
@@ -1,6 +1,7 @@
 // Copyright 2024 Yiyang Wu
 // SPDX-License-Identifier: MIT or GPL-2.0-or-later
 
+use super::errnos::*;
 use super::inode::*;
 use super::superblock::*;
 use super::*;
@@ -12,6 +13,9 @@ pub(crate) const MAP_FULL_MAPPED: u32 = 0x0008;
 pub(crate) const MAP_FRAGMENT: u32 = 0x0010;
 pub(crate) const MAP_PARTIAL_REF: u32 = 0x0020;
 
+/// Block address of a chunk which isn't backed by any data.
+pub(crate) const EROFS_NULL_ADDR: Blk = Blk::MAX;
+
 #[derive(Debug, Default)]
 #[repr(C)]
 pub(crate) struct Segment {
@@ -29,11 +33,13 @@ pub struct Map {
     pub(crate) map_type: MapType,
 }
 
-#[derive(Debug, Default)]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
 pub(crate) enum MapType {
     Meta,
     #[default]
     Normal,
+    /// Not backed by any physical data, reads as zeroes.
+    Hole,
 }
 
 impl From<MapType> for u32 {
@@ -41,27 +47,79 @@ impl From<MapType> for u32 {
         match value {
             MapType::Meta => MAP_META | MAP_MAPPED,
             MapType::Normal => MAP_MAPPED,
+            MapType::Hole => 0,
         }
     }
 }
 
+impl Map {
+    /// The length of the first piece of this map which doesn't cross a block boundary.
+    /// Holes are split on logical blocks, everything else on physical blocks.
+    pub(crate) fn block_len(&self, sb: &SuperBlock) -> Off {
+        match self.map_type {
+            MapType::Hole => self.logical.len.min(sb.blk_access(self.logical.start).len),
+            _ => self
+                .physical
+                .len
+                .min(sb.blk_access(self.physical.start).len),
+        }
+    }
+
+    pub(crate) fn is_hole(&self) -> bool {
+        matches!(self.map_type, MapType::Hole)
+    }
+
+    /// Cut the map down to its first `len` bytes.
+    pub(crate) fn truncate(&mut self, len: Off) {
+        self.logical.len = self.logical.len.min(len);
+        if !self.is_hole() {
+            self.physical.len = self.physical.len.min(len);
+        }
+    }
+
+    /// Append at most `max` bytes of `next` if it picks up right where this map ends, on the same
+    /// device or as a hole following a hole. Returns whether anything was appended.
+    pub(crate) fn merge(&mut self, next: &Map, max: Off) -> bool {
+        let contiguous = self.map_type == next.map_type
+            && next.logical.start == self.logical.start + self.logical.len
+            && match self.map_type {
+                MapType::Hole => true,
+                _ => {
+                    next.device_id == self.device_id
+                        && next.physical.start == self.physical.start + self.physical.len
+                }
+            };
+        let len = next.logical.len.min(max);
+        if !contiguous || len == 0 {
+            return false;
+        }
+        self.logical.len += len;
+        if !self.is_hole() {
+            self.physical.len += len;
+        }
+        true
+    }
+}
+
 pub(crate) type MapResult = PosixResult<Map>;
 
-/// Iterates over the data map represented by an inode.
+/// Iterates over the data map represented by an inode. By default every map covers at most one
+/// block so that page backed sources never have to cross a page.
 pub(crate) struct MapIter<'a, 'b, FS, I>
 where
-    FS: FileSystem<I>,
+    FS: FileSystem<I> + ?Sized,
     I: Inode,
 {
     fs: &'a FS,
     inode: &'b I,
     offset: Off,
     len: Off,
+    coalesce: Option<Off>,
 }
 
 impl<'a, 'b, FS, I> MapIter<'a, 'b, FS, I>
 where
-    FS: FileSystem<I>,
+    FS: FileSystem<I> + ?Sized,
     I: Inode,
 {
     pub(crate) fn new(fs: &'a FS, inode: &'b I, offset: Off) -> Self {
@@ -70,30 +128,62 @@ where
             inode,
             offset,
             len: inode.info().file_size(),
+            coalesce: None,
+        }
+    }
+
+    /// Stop after `len` bytes instead of at the end of the file.
+    pub(crate) fn until(mut self, len: Off) -> Self {
+        self.len = self.len.min(self.offset.saturating_add(len));
+        self
+    }
+
+    /// Merge contiguous extents into maps of up to `max` bytes instead of yielding single blocks.
+    /// Nothing changes for None.
+    pub(crate) fn coalesce(mut self, max: Option<Off>) -> Self {
+        self.coalesce = max;
+        self
+    }
+
+    fn next_map(&mut self) -> MapResult {
+        let rest = self.len - self.offset;
+        let mut m = self.fs.map(self.inode, self.offset)?;
+        match self.coalesce {
+            None => m.truncate(m.block_len(self.fs.superblock()).min(rest)),
+            Some(max) => {
+                let max = max.min(rest);
+                m.truncate(max);
+                while m.logical.len < max {
+                    let next = self.fs.map(self.inode, self.offset + m.logical.len)?;
+                    if !m.merge(&next, max - m.logical.len) {
+                        break;
+                    }
+                }
+            }
+        }
+        if m.logical.len == 0 {
+            return Err(EUCLEAN);
         }
+        self.offset += m.logical.len;
+        Ok(m)
     }
 }
 
 impl<'a, 'b, FS, I> Iterator for MapIter<'a, 'b, FS, I>
 where
-    FS: FileSystem<I>,
+    FS: FileSystem<I> + ?Sized,
     I: Inode,
 {
     type Item = MapResult;
     fn next(&mut self) -> Option<Self::Item> {
         if self.offset >= self.len {
-            None
-        } else {
-            let result = self.fs.map(self.inode, self.offset);
-            match result {
-                Ok(m) => {
-                    let accessor = self.fs.superblock().blk_access(m.physical.start);
-                    let len = m.physical.len.min(accessor.len);
-                    self.offset += len;
-                    Some(Ok(m))
-                }
-                Err(e) => Some(Err(e)),
-            }
+            return None;
+        }
+        let result = self.next_map();
+        if result.is_err() {
+            // Whatever comes after a broken map can't be trusted either.
+            self.offset = self.len;
         }
+        Some(result)
     }
 }
@@ -7,10 +7,13 @@
 use alloc::vec::Vec;
 
 use super::alloc_helper::*;
-use super::data::raw_iters::*;
+use super::dir::*;
 use super::errnos::*;
 use super::inode::*;
+use super::path::*;
 use super::superblock::*;
+use super::walk::*;
+use super::xattrs::acl::*;
 use super::xattrs::*;
 use super::*;
 
@@ -29,33 +32,21 @@ where
     collection.iget(nid, filesystem)
 }
 
-/// Lookup
+/// Lookup the path `name` relative to the directory `nid`. Symbolic links are followed except in
+/// the final component.
 pub fn lookup<'a, I, C>(
     filesystem: &'a dyn FileSystem<I>,
     collection: &'a mut C,
-    mut nid: Nid,
-    name: &str,
+    nid: Nid,
+    name: &[u8],
 ) -> PosixResult<&'a mut I>
 where
     I: Inode,
     C: InodeCollection<I = I>,
 {
-    for part in name.split('/') {
-        if part.is_empty() {
-            continue;
-        }
-        let inode = read_inode(filesystem, collection, nid)?; // this part collection is reborrowed for shorter
-                                                              // lifetime inside the loop;
-        match filesystem.find_nid(inode, part)? {
-            Some(n) => {
-                nid = n;
-            }
-            None => {
-                return Err(ENOENT);
-            }
-        }
-    }
-    read_inode(filesystem, collection, nid)
+    let options = ResolveOptions::new().follow_symlinks(false);
+    let resolved = resolve(filesystem, collection, nid, name, options)?;
+    read_inode(filesystem, collection, resolved.nid())
 }
 
 /// dir_lookup
@@ -63,7 +54,7 @@ pub fn dir_lookup<'a, I, C>(
     filesystem: &'a dyn FileSystem<I>,
     collection: &'a mut C,
     inode: &I,
-    name: &str,
+    name: &[u8],
 ) -> PosixResult<&'a mut I>
 where
     I: Inode,
@@ -89,7 +80,7 @@ where
 }
 
 /// list_xattr
-pub fn list_xattrs<I, C>(
+pub fn list_xattrs<I>(
     filesystem: &dyn FileSystem<I>,
     inode: &I,
     buffer: &mut [u8],
@@ -100,22 +91,267 @@ where
     filesystem.list_xattrs(inode, buffer)
 }
 
-pub(crate) fn get_xattr_infixes<'a>(
-    iter: &mut (dyn ContinuousBufferIter<'a> + 'a),
+/// xattrs
+pub fn xattrs<'a, I>(
+    filesystem: &'a dyn FileSystem<I>,
+    inode: &'a I,
+) -> PosixResult<XAttrIter<'a, I>>
+where
+    I: Inode,
+{
+    filesystem.xattrs(inode)
+}
+
+/// Iterate over the entries of a directory.
+pub fn read_dir<'a, I>(
+    filesystem: &'a dyn FileSystem<I>,
+    inode: &'a I,
+) -> PosixResult<ReadDir<'a, I>>
+where
+    I: Inode,
+{
+    filesystem.read_dir(inode)
+}
+
+/// Read and decode the access or default POSIX ACL of the inode.
+/// Returns None if the inode has no such ACL.
+pub fn get_acl<I>(
+    filesystem: &dyn FileSystem<I>,
+    inode: &I,
+    acl_type: AclType,
+) -> PosixResult<Option<PosixAcl>>
+where
+    I: Inode,
+{
+    match filesystem.get_xattr(inode, acl_type.xattr_index(), b"", &mut None) {
+        Ok(XAttrValue::Vec(value)) => PosixAcl::try_from(&value[..]).map(Some),
+        Ok(XAttrValue::Buffer(_)) => Err(EIO),
+        Err(ENODATA) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+fn link_size<I>(inode: &I) -> PosixResult<usize>
+where
+    I: Inode,
+{
+    if inode.info().inode_type() != Type::Link {
+        return Err(EINVAL);
+    }
+    match inode.info().file_size() {
+        0 => Err(EUCLEAN),
+        size if size > EROFS_PATH_MAX as Off => Err(ENAMETOOLONG),
+        size => Ok(size as usize),
+    }
+}
+
+/// Read the target of a symbolic link. Non-symlinks fail with EINVAL.
+pub fn readlink<I>(filesystem: &dyn FileSystem<I>, inode: &I) -> PosixResult<Vec<u8>>
+where
+    I: Inode,
+{
+    let mut target = vec_with_capacity(link_size(inode)?)?;
+    read_inode_data(filesystem, inode, 0, &mut target)?;
+    Ok(target)
+}
+
+/// Read the target of a symbolic link into `buffer` without allocating and return its length.
+/// The target is not NUL-terminated. A buffer of [`EROFS_PATH_MAX`] bytes always suffices,
+/// a shorter one that cannot hold the target fails with ERANGE.
+pub fn readlink_into<I>(
+    filesystem: &dyn FileSystem<I>,
+    inode: &I,
+    buffer: &mut [u8],
+) -> PosixResult<usize>
+where
+    I: Inode,
+{
+    let size = link_size(inode)?;
+    let target = buffer.get_mut(..size).ok_or(ERANGE)?;
+    read_inode_data(filesystem, inode, 0, target)?;
+    Ok(size)
+}
+
+/// Visit the directory `start` and everything beneath it in the given order, handing each entry
+/// with its path, metadata and parent to `visitor`, which decides whether to descend into it.
+/// Symbolic links are reported but never followed, inodes reached again under another path are
+/// flagged as hardlinks. Stops at the first error of either the filesystem or the visitor.
+pub fn walk<I>(
+    filesystem: &dyn FileSystem<I>,
+    start: Nid,
+    order: WalkOrder,
+    visitor: &mut dyn FnMut(&WalkEntry<'_, I>) -> PosixResult<WalkControl>,
+) -> PosixResult<()>
+where
+    I: Inode,
+{
+    super::walk::walk(filesystem, start, order, visitor)
+}
+
+/// Like [`walk`], but subdirectories are walked concurrently by `threads` threads, or as many as
+/// the machine has cores if zero. Entries are visited in no particular order, though a directory
+/// is always visited before its children and the first path reaching an inode is the one not
+/// flagged as a hardlink.
+#[cfg(feature = "std")]
+pub fn walk_parallel<I>(
+    filesystem: &(dyn FileSystem<I> + Sync),
+    start: Nid,
+    threads: usize,
+    visitor: &(dyn Fn(&WalkEntry<'_, I>) -> PosixResult<WalkControl> + Sync),
+) -> PosixResult<()>
+where
+    I: Inode,
+{
+    super::walk::walk_parallel(filesystem, start, threads, visitor)
+}
+
+/// Copy `buf.len()` bytes of the inode data starting at `offset` into `buf`.
+pub(crate) fn read_inode_data<I>(
+    filesystem: &dyn FileSystem<I>,
+    inode: &I,
+    offset: Off,
+    buf: &mut [u8],
+) -> PosixResult<()>
+where
+    I: Inode,
+{
+    if offset + buf.len() as Off > inode.info().file_size() {
+        return Err(EUCLEAN);
+    }
+    if buf.is_empty() {
+        return Ok(());
+    }
+    // Straight into the caller's buffer, so that bulk reads don't need temporary buffers.
+    if filesystem.read_at(inode, offset, buf)? != buf.len() {
+        return Err(EUCLEAN);
+    }
+    Ok(())
+}
+
+/// Decode `count` long xattr prefix records starting at `pos`.
+/// Each record is 4-byte aligned and consists of a le16 length followed by the base prefix index
+/// and the infix, as documented in https://erofs.docs.kernel.org/en/latest/ondisk/xattrs.html
+pub(crate) fn parse_xattr_infixes(
+    count: u8,
+    mut pos: Off,
+    read: &mut dyn FnMut(&mut [u8], Off) -> PosixResult<()>,
 ) -> PosixResult<Vec<XAttrInfix>> {
     let mut result: Vec<XAttrInfix> = Vec::new();
-    for data in iter {
-        let buffer = data?;
-        let buf = buffer.content();
-        let len = buf.len();
-        let mut cur: usize = 0;
-        while cur <= len {
-            let mut infix: Vec<u8> = Vec::new();
-            let size = u16::from_le_bytes([buf[cur], buf[cur + 1]]) as usize;
-            extend_from_slice(&mut infix, &buf[cur + 2..cur + 2 + size])?;
-            push_vec(&mut result, XAttrInfix(infix))?;
-            cur = round!(UP, cur + 2 + size, 4);
+    for _ in 0..count {
+        pos = round!(UP, pos, 4);
+        let mut len = [0u8; 2];
+        read(&mut len, pos)?;
+        pos += len.len() as Off;
+        let len = u16::from_le_bytes(len) as usize;
+        if len == 0 || len > EROFS_NAME_LEN + 1 {
+            return Err(EUCLEAN);
         }
+        let mut infix = vec_with_capacity(len)?;
+        read(&mut infix, pos)?;
+        pos += len as Off;
+        push_vec(&mut result, XAttrInfix(infix))?;
     }
     Ok(result)
 }
+
+/// Load the long xattr prefixes. They live in the packed inode if there is one and in the
+/// metadata area otherwise.
+pub(crate) fn get_xattr_infixes(
+    filesystem: &dyn FileSystem<MetaInode>,
+) -> PosixResult<Vec<XAttrInfix>> {
+    let sb = filesystem.superblock();
+    if sb.feature_incompat & EROFS_FEATURE_INCOMPAT_XATTR_PREFIXES == 0 {
+        return Ok(Vec::new());
+    }
+    let pos = (sb.xattr_prefix_start as u32 as Off) << 2;
+    match sb.packed_nid() {
+        Some(nid) => {
+            let info = filesystem.read_inode_info(nid)?;
+            match info.format().layout() {
+                Layout::FlatPlain | Layout::FlatInline | Layout::Chunk => {}
+                // Compressed packed inodes can't be read here. Rather than refusing the whole
+                // image, go without long prefixes: xattrs using them are skipped.
+                _ => return Ok(Vec::new()),
+            }
+            let inode = MetaInode::new(
+                sb,
+                info,
+                nid,
+                XAttrSharedEntries {
+                    name_filter: 0,
+                    shared_indexes: Vec::new(),
+                },
+            );
+            parse_xattr_infixes(sb.xattr_prefix_count, pos, &mut |buf, offset| {
+                read_inode_data(filesystem, &inode, offset, buf)
+            })
+        }
+        None => parse_xattr_infixes(sb.xattr_prefix_count, pos, &mut |buf, offset| {
+            if filesystem.backend().fill(buf, 0, offset)? != buf.len() as u64 {
+                return Err(EUCLEAN);
+            }
+            Ok(())
+        }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encode_infixes(records: &[(u8, &[u8])]) -> Vec<u8> {
+        let mut buf = Vec::new();
+        for (index, infix) in records {
+            buf.resize(round!(UP, buf.len(), 4), 0);
+            buf.extend_from_slice(&(infix.len() as u16 + 1).to_le_bytes());
+            buf.push(*index);
+            buf.extend_from_slice(infix);
+        }
+        buf
+    }
+
+    fn read_from(data: &[u8]) -> impl FnMut(&mut [u8], Off) -> PosixResult<()> + '_ {
+        |buf, offset| {
+            let start = offset as usize;
+            match data.get(start..start + buf.len()) {
+                Some(src) => {
+                    buf.copy_from_slice(src);
+                    Ok(())
+                }
+                None => Err(EUCLEAN),
+            }
+        }
+    }
+
+    #[test]
+    fn test_parse_xattr_infixes() {
+        let mut data = [0u8; 8].to_vec();
+        data.extend(encode_infixes(&[(1, b"overlay."), (4, b"ab")]));
+        let infixes = parse_xattr_infixes(2, 8, &mut read_from(&data)).unwrap();
+        assert_eq!(infixes.len(), 2);
+        assert_eq!(infixes[0].prefix(), Some(&b"user."[..]));
+        assert_eq!(infixes[0].name(), b"overlay.");
+        assert_eq!(infixes[1].prefix_index(), 4);
+        assert_eq!(infixes[1].name(), b"ab");
+        assert!(parse_xattr_infixes(0, 8, &mut read_from(&data))
+            .unwrap()
+            .is_empty());
+
+        // More records than stored must not read past the end.
+        assert_eq!(
+            parse_xattr_infixes(3, 8, &mut read_from(&data)).unwrap_err(),
+            EUCLEAN
+        );
+        // Truncated record.
+        assert_eq!(
+            parse_xattr_infixes(2, 8, &mut read_from(&data[..data.len() - 1])).unwrap_err(),
+            EUCLEAN
+        );
+        // Zero length record.
+        data[8] = 0;
+        assert_eq!(
+            parse_xattr_infixes(1, 8, &mut read_from(&data)).unwrap_err(),
+            EUCLEAN
+        );
+    }
+}
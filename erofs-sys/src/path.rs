@@ -0,0 +1,289 @@
+// Copyright 2024 Yiyang Wu
+// SPDX-License-Identifier: MIT or GPL-2.0-or-later
+
+use alloc::vec::Vec;
+
+use super::alloc_helper::*;
+use super::errnos::*;
+use super::inode::*;
+use super::operations::*;
+use super::superblock::*;
+use super::*;
+
+/// The maximum length of a path, including symbolic link targets.
+pub const EROFS_PATH_MAX: usize = 4096;
+/// The default number of symbolic links followed before giving up with ELOOP, same as Linux.
+pub const EROFS_MAX_SYMLINK_HOPS: usize = 40;
+
+/// Options controlling how [`resolve`] walks a path.
+#[derive(Debug, Clone, Copy)]
+pub struct ResolveOptions {
+    follow: bool,
+    max_hops: usize,
+    beneath: bool,
+}
+
+impl Default for ResolveOptions {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ResolveOptions {
+    /// Follow every symbolic link, including the final component, up to
+    /// [`EROFS_MAX_SYMLINK_HOPS`] times and allow escaping the starting directory.
+    pub fn new() -> Self {
+        Self {
+            follow: true,
+            max_hops: EROFS_MAX_SYMLINK_HOPS,
+            beneath: false,
+        }
+    }
+
+    /// Whether a symbolic link in the final component is followed. Links in the middle of a path
+    /// and links in a final component with a trailing slash are always followed.
+    pub fn follow_symlinks(mut self, follow: bool) -> Self {
+        self.follow = follow;
+        self
+    }
+
+    /// The number of symbolic links that may be followed before the walk fails with ELOOP.
+    pub fn max_symlink_hops(mut self, hops: usize) -> Self {
+        self.max_hops = hops;
+        self
+    }
+
+    /// Confine the walk beneath the starting directory. Absolute paths, absolute symbolic links
+    /// and `..` leaving the starting directory fail with EXDEV.
+    pub fn beneath(mut self, beneath: bool) -> Self {
+        self.beneath = beneath;
+        self
+    }
+}
+
+/// The outcome of a successful [`resolve`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Resolved {
+    nid: Nid,
+    parent: Nid,
+}
+
+impl Resolved {
+    /// The nid the path resolves to.
+    pub fn nid(&self) -> Nid {
+        self.nid
+    }
+    /// The nid of the directory holding the final component.
+    pub fn parent(&self) -> Nid {
+        self.parent
+    }
+}
+
+fn read_dir_inode<'a, I, C>(
+    filesystem: &'a dyn FileSystem<I>,
+    collection: &'a mut C,
+    nid: Nid,
+) -> PosixResult<&'a mut I>
+where
+    I: Inode,
+    C: InodeCollection<I = I>,
+{
+    let inode = read_inode(filesystem, collection, nid)?;
+    if inode.info().inode_type() != Type::Directory {
+        return Err(ENOTDIR);
+    }
+    Ok(inode)
+}
+
+fn find_child<I, C>(
+    filesystem: &dyn FileSystem<I>,
+    collection: &mut C,
+    dir: Nid,
+    name: &[u8],
+) -> PosixResult<Nid>
+where
+    I: Inode,
+    C: InodeCollection<I = I>,
+{
+    let inode = read_dir_inode(filesystem, collection, dir)?;
+    filesystem.find_nid(inode, name)?.ok_or(ENOENT)
+}
+
+/// Resolve `path` starting from the directory `start`. Absolute paths and absolute symbolic link
+/// targets restart from the root directory, relative link targets continue from the directory
+/// holding the link. `..` in the root directory stays there.
+pub fn resolve<I, C>(
+    filesystem: &dyn FileSystem<I>,
+    collection: &mut C,
+    start: Nid,
+    path: &[u8],
+    options: ResolveOptions,
+) -> PosixResult<Resolved>
+where
+    I: Inode,
+    C: InodeCollection<I = I>,
+{
+    let root = filesystem.superblock().root_nid as Nid;
+    if path.len() > EROFS_PATH_MAX {
+        return Err(ENAMETOOLONG);
+    }
+    // Directories walked through so far, so that `..` does not have to trust the on-disk entry.
+    let mut ancestors: Vec<Nid> = Vec::new();
+    let mut cur = start;
+    let mut hops = 0;
+    let mut rest: Vec<u8> = Vec::new();
+    extend_from_slice(&mut rest, path)?;
+    let mut pos = 0;
+
+    if rest.first() == Some(&b'/') {
+        if options.beneath {
+            return Err(EXDEV);
+        }
+        cur = root;
+    }
+
+    loop {
+        while rest.get(pos) == Some(&b'/') {
+            pos += 1;
+        }
+        if pos == rest.len() {
+            break;
+        }
+        let end = rest[pos..]
+            .iter()
+            .position(|c| *c == b'/')
+            .map_or(rest.len(), |i| pos + i);
+        let name = &rest[pos..end];
+        let trailing = end != rest.len();
+        pos = end;
+
+        match name {
+            b"." => {
+                read_dir_inode(filesystem, collection, cur)?;
+                continue;
+            }
+            b".." => {
+                read_dir_inode(filesystem, collection, cur)?;
+                if let Some(parent) = ancestors.pop() {
+                    cur = parent;
+                } else if options.beneath {
+                    return Err(EXDEV);
+                } else if cur != root {
+                    cur = find_child(filesystem, collection, cur, b"..")?;
+                }
+                continue;
+            }
+            _ => {}
+        }
+        if name.len() > EROFS_NAME_LEN {
+            return Err(ENAMETOOLONG);
+        }
+
+        let nid = find_child(filesystem, collection, cur, name)?;
+        let inode = read_inode(filesystem, collection, nid)?;
+        match inode.info().inode_type() {
+            Type::Link if trailing || options.follow => {
+                hops += 1;
+                if hops > options.max_hops {
+                    return Err(ELOOP);
+                }
+                let target = readlink(filesystem, inode)?;
+                if target[0] == b'/' {
+                    if options.beneath {
+                        return Err(EXDEV);
+                    }
+                    ancestors.clear();
+                    cur = root;
+                }
+                // Splice the target in front of whatever is left of the path, which is either
+                // empty or starts with a slash.
+                let mut next = target;
+                extend_from_slice(&mut next, &rest[pos..])?;
+                if next.len() > EROFS_PATH_MAX {
+                    return Err(ENAMETOOLONG);
+                }
+                rest = next;
+                pos = 0;
+            }
+            Type::Directory => {
+                push_vec(&mut ancestors, cur)?;
+                cur = nid;
+            }
+            _ if trailing => return Err(ENOTDIR),
+            _ => {
+                push_vec(&mut ancestors, cur)?;
+                cur = nid;
+            }
+        }
+    }
+
+    let parent = match ancestors.last() {
+        Some(parent) => *parent,
+        None if cur == root => root,
+        None => find_child(filesystem, collection, cur, b"..")?,
+    };
+    Ok(Resolved { nid: cur, parent })
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::super::data::backends::uncompressed::*;
+    use super::super::file::ImageFileSystem;
+    use super::super::inode::tests::*;
+    use super::super::superblock::tests::*;
+    use super::*;
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_resolve_symlink_loops() {
+        let mut image =
+            build_dir_image(&[(b".", 0, 2), (b"..", 0, 2), (b"loop", 1, 7), (b"abs", 2, 7)]);
+        add_symlink(&mut image, 1, b"./loop");
+        add_symlink(&mut image, 2, b"/");
+        let fs =
+            ImageFileSystem::try_new(UncompressedBackend::new(CountingSource::new(image))).unwrap();
+        let filesystem: &dyn FileSystem<SimpleInode> = &fs;
+        let mut inodes: HashMap<Nid, SimpleInode> = HashMap::new();
+        let options = ResolveOptions::new();
+
+        assert_eq!(
+            resolve(filesystem, &mut inodes, 0, b"loop", options),
+            Err(ELOOP)
+        );
+        assert_eq!(
+            resolve(filesystem, &mut inodes, 0, b"loop/x", options),
+            Err(ELOOP)
+        );
+        assert_eq!(
+            resolve(
+                filesystem,
+                &mut inodes,
+                0,
+                b"loop",
+                options.follow_symlinks(false)
+            ),
+            Ok(Resolved { nid: 1, parent: 0 })
+        );
+
+        assert_eq!(
+            resolve(filesystem, &mut inodes, 0, b"abs/abs/abs", options),
+            Ok(Resolved { nid: 0, parent: 0 })
+        );
+        assert_eq!(
+            resolve(
+                filesystem,
+                &mut inodes,
+                0,
+                b"abs/abs/abs",
+                options.max_symlink_hops(2)
+            ),
+            Err(ELOOP)
+        );
+        assert_eq!(
+            resolve(filesystem, &mut inodes, 0, b"abs", options.beneath(true)),
+            Err(EXDEV)
+        );
+    }
+}
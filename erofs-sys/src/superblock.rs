@@ -12,6 +12,7 @@ use super::dir::*;
 use super::errnos::*;
 use super::inode::*;
 use super::map::*;
+use super::operations::read_inode_data;
 use super::xattrs::*;
 use super::*;
 
@@ -102,6 +103,14 @@ impl From<[u8; 128]> for SuperBlock {
     }
 }
 
+pub(crate) const EROFS_SUPER_MAGIC_V1: u32 = 0xE0F5_E1E2;
+pub(crate) const EROFS_MIN_BLKSZBITS: u8 = 9;
+pub(crate) const EROFS_MAX_BLKSZBITS: u8 = 16;
+
+pub(crate) const EROFS_FEATURE_COMPAT_XATTR_FILTER: i32 = 0x0000_0004;
+pub(crate) const EROFS_FEATURE_INCOMPAT_FRAGMENTS: i32 = 0x0000_0020;
+pub(crate) const EROFS_FEATURE_INCOMPAT_XATTR_PREFIXES: i32 = 0x0000_0040;
+
 pub(crate) type SuperBlockBuf = [u8; size_of::<SuperBlock>()];
 pub(crate) const SUPERBLOCK_EMPTY_BUF: SuperBlockBuf = [0; size_of::<SuperBlock>()];
 
@@ -144,6 +153,23 @@ impl SuperBlock {
         1 << self.blkszbits
     }
 
+    /// Reject superblocks which aren't EROFS or whose geometry the rest of the code can't cope
+    /// with, so that block and offset calculations never overflow.
+    pub(crate) fn validate(&self) -> PosixResult<()> {
+        if self.magic != EROFS_SUPER_MAGIC_V1
+            || !(EROFS_MIN_BLKSZBITS..=EROFS_MAX_BLKSZBITS).contains(&self.blkszbits)
+            || self.blkszbits as u32 + self.dirblkbits as u32 > EROFS_MAX_BLKSZBITS as u32
+        {
+            return Err(EINVAL);
+        }
+        Ok(())
+    }
+
+    /// The size of a directory block, which may be a multiple of the block size.
+    pub fn dirblksz(&self) -> Off {
+        1 << (self.blkszbits + self.dirblkbits)
+    }
+
     /// blk_round_up
     pub fn blk_round_up(&self, addr: Off) -> Blk {
         ((addr + self.blksz() - 1) >> self.blkszbits) as Blk
@@ -158,6 +184,21 @@ impl SuperBlock {
         self.blkpos(self.meta_blkaddr) + ((nid as Off) << (5 as Off))
     }
 
+    /// Whether the xattr name bloom filter of each inode can be trusted.
+    pub fn has_xattr_filter(&self) -> bool {
+        self.feature_compat & EROFS_FEATURE_COMPAT_XATTR_FILTER != 0
+            && self.xattr_filter_reserved == 0
+    }
+
+    /// The nid of the packed inode which holds fragments and possibly the long xattr prefixes.
+    pub fn packed_nid(&self) -> Option<Nid> {
+        if self.feature_incompat & EROFS_FEATURE_INCOMPAT_FRAGMENTS != 0 && self.packed_nid > 0 {
+            Some(self.packed_nid as Nid)
+        } else {
+            None
+        }
+    }
+
     pub(crate) fn chunk_access(&self, format: ChunkFormat, address: Off) -> Accessor {
         let chunkbits = format.chunkbits() + self.blkszbits as u16;
         Accessor::new(address, chunkbits as Off)
@@ -229,8 +270,9 @@ where
             _ => Err(EUCLEAN),
         }?;
         let accessor = sb.chunk_access(chunkformat, offset);
+        let len = accessor.len.min(inode.info().file_size() - offset);
 
-        if chunkformat.is_chunkindex() {
+        let (blkaddr, device_id) = if chunkformat.is_chunkindex() {
             let unit = size_of::<ChunkIndex>() as Off;
             let pos = round!(
                 UP,
@@ -243,23 +285,10 @@ where
             let mut buf = [0u8; size_of::<ChunkIndex>()];
             self.backend().fill(&mut buf, 0, pos)?;
             let chunk_index = ChunkIndex::from(buf);
-            if chunk_index.blkaddr == u32::MAX {
-                Err(EUCLEAN)
-            } else {
-                Ok(Map {
-                    logical: Segment {
-                        start: accessor.base + accessor.off,
-                        len: accessor.len,
-                    },
-                    physical: Segment {
-                        start: sb.blkpos(chunk_index.blkaddr) + accessor.off,
-                        len: accessor.len,
-                    },
-                    algorithm_format: 0,
-                    device_id: chunk_index.device_id & self.device_info().mask,
-                    map_type: MapType::Normal,
-                })
-            }
+            (
+                chunk_index.blkaddr,
+                chunk_index.device_id & self.device_info().mask,
+            )
         } else {
             let unit = 4;
             let pos = round!(
@@ -272,36 +301,90 @@ where
             );
             let mut buf = [0u8; 4];
             self.backend().fill(&mut buf, 0, pos)?;
-            let blkaddr = u32::from_le_bytes(buf);
-            let len = accessor.len.min(inode.info().file_size() - offset);
-            if blkaddr == u32::MAX {
-                Err(EUCLEAN)
-            } else {
-                Ok(Map {
-                    logical: Segment {
-                        start: accessor.base + accessor.off,
-                        len,
-                    },
-                    physical: Segment {
-                        start: sb.blkpos(blkaddr) + accessor.off,
-                        len,
-                    },
-                    algorithm_format: 0,
-                    device_id: 0,
-                    map_type: MapType::Normal,
-                })
-            }
+            (u32::from_le_bytes(buf), 0)
+        };
+
+        let logical = Segment {
+            start: accessor.base + accessor.off,
+            len,
+        };
+        if blkaddr == EROFS_NULL_ADDR {
+            // Unallocated chunks of sparse files read back as zeroes.
+            return Ok(Map {
+                logical,
+                physical: Segment::default(),
+                algorithm_format: 0,
+                device_id: 0,
+                map_type: MapType::Hole,
+            });
         }
+        Ok(Map {
+            logical,
+            physical: Segment {
+                start: sb.blkpos(blkaddr) + accessor.off,
+                len,
+            },
+            algorithm_format: 0,
+            device_id,
+            map_type: MapType::Normal,
+        })
     }
 
     /// Map
     fn map(&self, inode: &I, offset: Off) -> MapResult {
+        if offset >= inode.info().file_size() {
+            return Err(EINVAL);
+        }
         match inode.info().format().layout() {
             Layout::FlatInline => self.flatmap(inode, offset, true),
             Layout::FlatPlain => self.flatmap(inode, offset, false),
             Layout::Chunk => self.chunk_map(inode, offset),
-            _ => todo!(),
+            // Compressed layouts aren't supported yet.
+            _ => Err(EOPNOTSUPP),
+        }
+    }
+
+    /// The largest read issued to the backend at once. File backed filesystems merge physically
+    /// contiguous extents into reads of up to this size, while None keeps every read within a
+    /// block so that page backed sources never have to cross a page.
+    fn max_io_len(&self) -> Option<Off> {
+        None
+    }
+
+    /// Read file data starting at `offset` into `buf` and return the number of bytes read, which
+    /// is only short at the end of the file. Holes read as zeroes. Data is read straight into
+    /// `buf`, with one backend read per contiguous extent of up to [`FileSystem::max_io_len`].
+    fn read_at(&self, inode: &I, offset: Off, buf: &mut [u8]) -> PosixResult<usize> {
+        let file_size = inode.info().file_size();
+        if offset >= file_size {
+            return Ok(0);
+        }
+        let total = (file_size - offset).min(buf.len() as Off) as usize;
+        let mut cur = 0;
+        let maps = MapIter::new(self.as_filesystem(), inode, offset)
+            .until(total as Off)
+            .coalesce(self.max_io_len());
+        for map in maps {
+            let map = map?;
+            let data = buf
+                .get_mut(cur..cur + map.logical.len as usize)
+                .ok_or(EUCLEAN)?;
+            if map.is_hole() {
+                data.fill(0);
+            } else {
+                let read = self
+                    .backend()
+                    .fill(data, map.device_id as i32, map.physical.start)?;
+                if read != data.len() as u64 {
+                    return Err(EUCLEAN);
+                }
+            }
+            cur += data.len();
+        }
+        if cur != total {
+            return Err(EUCLEAN);
         }
+        Ok(total)
     }
 
     // TODO:: Remove the Box<dyn Iterator> here
@@ -317,6 +400,16 @@ where
         offset: Off,
     ) -> PosixResult<Box<dyn BufferMapIter<'a> + 'b>>;
 
+    /// Like [`FileSystem::mapped_iter`] but stops after `len` bytes. File backed filesystems
+    /// yield buffers of up to [`FileSystem::max_io_len`] bytes covering contiguous extents
+    /// instead of single blocks.
+    fn mapped_range_iter<'b, 'a: 'b>(
+        &'a self,
+        inode: &'b I,
+        offset: Off,
+        len: Off,
+    ) -> PosixResult<Box<dyn BufferMapIter<'a> + 'b>>;
+
     /// ContinousIter
     fn continuous_iter<'a>(
         &'a self,
@@ -329,18 +422,54 @@ where
         (self.as_filesystem(), nid).try_into()
     }
     /// Findnid
-    fn find_nid(&self, inode: &I, name: &str) -> PosixResult<Option<Nid>> {
-        for buf in self.mapped_iter(inode, 0)? {
-            for dirent in buf?.iter_dir() {
-                if dirent.dirname() == name.as_bytes() {
-                    return Ok(Some(dirent.desc.nid));
-                }
+    /// Directory entries are sorted by name both within and across blocks, so the block whose
+    /// first name is the greatest one not above the target is binary searched first and then the
+    /// entries inside of it, which takes O(log n) block reads like the kernel does.
+    fn find_nid(&self, inode: &I, name: &[u8]) -> PosixResult<Option<Nid>> {
+        let sb = self.superblock();
+        let dirblksz = sb.dirblksz();
+        let (mut head, mut back) = (0, round!(UP, inode.info().file_size(), dirblksz) / dirblksz);
+        while head < back {
+            let mid = head + (back - head) / 2;
+            let buf = self.dir_block(inode, mid * dirblksz)?;
+            let collection = buf.iter_dir();
+            match collection.binary_search(name)? {
+                Ok(dirent) => return Ok(Some(dirent.desc.nid)),
+                // Smaller than the first name of this block.
+                Err(0) => back = mid,
+                // Greater than the last name of this block.
+                Err(pos) if pos == collection.total()? => head = mid + 1,
+                Err(_) => return Ok(None),
             }
         }
         Ok(None)
     }
 
     // Readdir related goes here.
+    /// Read the directory block starting at `offset`. When directory blocks are larger than
+    /// filesystem blocks, the pieces are gathered into a single buffer.
+    fn dir_block<'a>(&'a self, inode: &I, offset: Off) -> PosixResult<BlockBuffer<'a>> {
+        let sb = self.superblock();
+        if sb.dirblkbits == 0 {
+            return self.mapped_iter(inode, offset)?.next().ok_or(EUCLEAN)?;
+        }
+        let file_size = inode.info().file_size();
+        if offset >= file_size {
+            return Err(EUCLEAN);
+        }
+        let len = sb.dirblksz().min(file_size - offset) as usize;
+        let mut block = vec_with_capacity(len)?;
+        resize_vec(&mut block, len, 0)?;
+        read_inode_data(self.as_filesystem(), inode, offset, &mut block)?;
+        Ok(TempBuffer::new(block, 0, len).into())
+    }
+    /// Iterate over the entries of a directory.
+    fn read_dir<'a>(&'a self, inode: &'a I) -> PosixResult<ReadDir<'a, I>> {
+        if inode.info().inode_type() != Type::Directory {
+            return Err(ENOTDIR);
+        }
+        Ok(ReadDir::new(self.as_filesystem(), inode))
+    }
     /// FillDentries
     fn fill_dentries(
         &self,
@@ -350,42 +479,30 @@ where
         emitter: &mut dyn FnMut(Dirent<'_>, Off) -> bool,
     ) -> PosixResult<()> {
         let sb = self.superblock();
-        let accessor = sb.blk_access(offset);
-        if offset > inode.info().file_size() {
+        let (dirblksz, file_size) = (sb.dirblksz(), inode.info().file_size());
+        if offset > file_size {
             return Err(EUCLEAN);
         }
 
-        let map_offset = round!(DOWN, offset, sb.blksz());
-        let blk_offset = round!(UP, accessor.off, size_of::<DirentDesc>() as Off);
-
-        let mut map_iter = self.mapped_iter(inode, map_offset)?;
-        let first_buf = map_iter.next().unwrap()?;
-        let mut collection = first_buf.iter_dir();
+        let unit = size_of::<DirentDesc>() as Off;
+        let mut blkstart = round!(DOWN, offset, dirblksz);
+        let mut index = round!(UP, offset - blkstart, unit) / unit;
         let mut cnt = 0;
-        let mut pos: Off = map_offset + blk_offset;
-
-        if blk_offset as usize / size_of::<DirentDesc>() <= collection.total() {
-            collection.skip_dir(blk_offset as usize / size_of::<DirentDesc>());
+        while blkstart < file_size {
+            let buf = self.dir_block(inode, blkstart)?;
+            let mut collection = buf.iter_dir();
+            let mut pos = blkstart + index * unit;
+            collection.skip_dir(index as usize);
             for dirent in collection {
+                let dirent = dirent?;
                 if cnt >= skipents && emitter(dirent, pos) {
                     return Ok(());
                 }
-                pos += size_of::<DirentDesc>() as Off;
-                cnt += 1;
-            }
-        }
-
-        pos = round!(UP, pos, sb.blksz());
-
-        for buf in map_iter {
-            for dirent in buf?.iter_dir() {
-                if cnt >= skipents && emitter(dirent, pos) {
-                    return Ok(());
-                }
-                pos += size_of::<DirentDesc>() as Off;
+                pos += unit;
                 cnt += 1;
             }
-            pos = round!(UP, pos, sb.blksz());
+            blkstart += dirblksz;
+            index = 0;
         }
         Ok(())
     }
@@ -393,6 +510,20 @@ where
     // Extended attributes goes here.
     /// XattrInfixes
     fn xattr_infixes(&self) -> &Vec<XAttrInfix>;
+    /// XattrSharedCache
+    fn xattr_shared_cache(&self) -> Option<&XAttrSharedCache> {
+        None
+    }
+    /// Decode the shared xattr entry at the given index from the backend.
+    fn read_xattr_shared_entry(&self, index: u32) -> PosixResult<XAttrEntry> {
+        let sb = self.superblock();
+        let mut provider = SkippableContinuousIter::try_new(
+            self.continuous_iter(sb.blkpos(sb.xattr_blkaddr) + (index as Off) * 4, u64::MAX)?,
+        )?
+        .ok_or(EUCLEAN)?;
+        let header = provider.get_entry_header()?;
+        provider.read_xattr_entry(self.xattr_infixes(), &header)
+    }
     // Currently we eagerly initialized all xattrs;
     /// xattrs
     fn read_inode_xattrs_shared_entries(
@@ -439,34 +570,24 @@ where
         buffer: &mut Option<&mut [u8]>,
     ) -> PosixResult<XAttrValue> {
         let sb = self.superblock();
-        let shared_count = inode.xattrs_shared_entries().shared_indexes.len();
-        let inline_offset = sb.iloc(inode.nid())
-            + inode.info().inode_size() as Off
-            + size_of::<XAttrSharedEntrySummary>() as Off
-            + 4 * shared_count as Off;
-
-        let inline_header_sz =
-            size_of::<XAttrSharedEntrySummary>() as Off + shared_count as Off * 4;
-
-        if inline_header_sz <= inode.info().xattr_size() {
-            let inline_len = inode.info().xattr_size() - inline_header_sz;
-            if let Some(mut inline_provider) =
-                SkippableContinuousIter::try_new(self.continuous_iter(inline_offset, inline_len)?)?
-            {
-                while !inline_provider.eof() {
-                    let header = inline_provider.get_entry_header()?;
-                    match inline_provider.query_xattr_value(
-                        self.xattr_infixes(),
-                        &header,
-                        name,
-                        index,
-                        buffer,
-                    ) {
-                        Ok(value) => return Ok(value),
-                        Err(e) => {
-                            if e != ENODATA {
-                                return Err(e);
-                            }
+        if sb.has_xattr_filter() && !inode.xattrs_shared_entries().may_contain(index, name) {
+            return Err(ENODATA);
+        }
+
+        if let Some(mut inline_provider) = inline_xattrs_provider(self.as_filesystem(), inode)? {
+            while !inline_provider.eof() {
+                let header = inline_provider.get_entry_header()?;
+                match inline_provider.query_xattr_value(
+                    self.xattr_infixes(),
+                    &header,
+                    name,
+                    index,
+                    buffer,
+                ) {
+                    Ok(value) => return Ok(value),
+                    Err(e) => {
+                        if e != ENODATA {
+                            return Err(e);
                         }
                     }
                 }
@@ -474,19 +595,37 @@ where
         }
 
         for entry_index in inode.xattrs_shared_entries().shared_indexes.iter() {
-            let mut shared_provider = SkippableContinuousIter::try_new(self.continuous_iter(
-                sb.blkpos(self.superblock().xattr_blkaddr) + (*entry_index as Off) * 4,
-                u64::MAX,
-            )?)?
-            .unwrap();
-            let header = shared_provider.get_entry_header()?;
-            match shared_provider.query_xattr_value(
-                self.xattr_infixes(),
-                &header,
-                name,
-                index,
-                buffer,
-            ) {
+            let result = match self.xattr_shared_cache() {
+                Some(cache) => cache.with_entry(
+                    *entry_index,
+                    sb.blksz() as usize,
+                    || self.read_xattr_shared_entry(*entry_index),
+                    |entry| {
+                        if entry.matches(index, name) {
+                            entry.copy_value(buffer)
+                        } else {
+                            Err(ENODATA)
+                        }
+                    },
+                ),
+                None => {
+                    let mut shared_provider =
+                        SkippableContinuousIter::try_new(self.continuous_iter(
+                            sb.blkpos(self.superblock().xattr_blkaddr) + (*entry_index as Off) * 4,
+                            u64::MAX,
+                        )?)?
+                        .ok_or(EUCLEAN)?;
+                    let header = shared_provider.get_entry_header()?;
+                    shared_provider.query_xattr_value(
+                        self.xattr_infixes(),
+                        &header,
+                        name,
+                        index,
+                        buffer,
+                    )
+                }
+            };
+            match result {
                 Ok(value) => return Ok(value),
                 Err(e) => {
                     if e != ENODATA {
@@ -498,50 +637,61 @@ where
 
         Err(ENODATA)
     }
-    /// list_xattrs
+    /// List the names of all the xattrs of the inode as NUL-terminated strings into the buffer
+    /// and return the length written. Following listxattr(2), an empty buffer only queries the
+    /// length needed and a buffer too small for the names fails with ERANGE.
     fn list_xattrs(&self, inode: &I, buffer: &mut [u8]) -> PosixResult<usize> {
         let sb = self.superblock();
-        let shared_count = inode.xattrs_shared_entries().shared_indexes.len();
-        let inline_offset = sb.iloc(inode.nid())
-            + inode.info().inode_size() as Off
-            + size_of::<XAttrSharedEntrySummary>() as Off
-            + shared_count as Off * 4;
-        let inline_header_sz =
-            size_of::<XAttrSharedEntrySummary>() as Off + shared_count as Off * 4;
+        let size_only = buffer.is_empty();
         let mut offset = 0;
 
-        if inline_header_sz <= inode.info().xattr_size() {
-            let inline_len = inode.info().xattr_size() - inline_header_sz;
-            if let Some(mut inline_provider) =
-                SkippableContinuousIter::try_new(self.continuous_iter(inline_offset, inline_len)?)?
-            {
-                while !inline_provider.eof() {
-                    let header = inline_provider.get_entry_header()?;
-                    offset += inline_provider.get_xattr_key(
-                        self.xattr_infixes(),
-                        &header,
-                        &mut buffer[offset..],
-                    )?;
-                    inline_provider.skip_xattr_value(&header)?;
-                }
+        if let Some(mut inline_provider) = inline_xattrs_provider(self.as_filesystem(), inode)? {
+            while !inline_provider.eof() {
+                let header = inline_provider.get_entry_header()?;
+                offset += inline_provider.get_xattr_key(
+                    self.xattr_infixes(),
+                    &header,
+                    (!size_only).then(|| &mut buffer[offset..]),
+                )?;
+                inline_provider.skip_xattr_value(&header)?;
             }
         }
 
         for index in inode.xattrs_shared_entries().shared_indexes.iter() {
-            let mut shared_provider = SkippableContinuousIter::try_new(self.continuous_iter(
-                sb.blkpos(self.superblock().xattr_blkaddr) + (*index as Off) * 4,
-                u64::MAX,
-            )?)?
-            .unwrap();
-            let header = shared_provider.get_entry_header()?;
-            offset += shared_provider.get_xattr_key(
-                self.xattr_infixes(),
-                &header,
-                &mut buffer[offset..],
-            )?;
+            let result = match self.xattr_shared_cache() {
+                Some(cache) => cache.with_entry(
+                    *index,
+                    sb.blksz() as usize,
+                    || self.read_xattr_shared_entry(*index),
+                    |entry| entry.write_key((!size_only).then(|| &mut buffer[offset..])),
+                ),
+                None => {
+                    let mut shared_provider =
+                        SkippableContinuousIter::try_new(self.continuous_iter(
+                            sb.blkpos(self.superblock().xattr_blkaddr) + (*index as Off) * 4,
+                            u64::MAX,
+                        )?)?
+                        .ok_or(EUCLEAN)?;
+                    let header = shared_provider.get_entry_header()?;
+                    shared_provider.get_xattr_key(
+                        self.xattr_infixes(),
+                        &header,
+                        (!size_only).then(|| &mut buffer[offset..]),
+                    )
+                }
+            };
+            match result {
+                Ok(len) => offset += len,
+                Err(ENODATA) => continue,
+                Err(e) => return Err(e),
+            }
         }
         Ok(offset)
     }
+    /// Iterate over all the xattrs of the inode in on-disk order.
+    fn xattrs<'a>(&'a self, inode: &'a I) -> PosixResult<XAttrIter<'a, I>> {
+        XAttrIter::try_new(self.as_filesystem(), inode)
+    }
 }
 
 /// Superblock Info
@@ -579,6 +729,8 @@ pub(crate) mod tests {
 
     use super::inode::tests::*;
     use super::operations::*;
+    use super::path::*;
+    use super::xattrs::acl::*;
     use super::*;
 
     use hex_literal::hex;
@@ -589,8 +741,14 @@ pub(crate) mod tests {
     use std::path::Path;
     use std::string::ToString;
     use std::vec;
+    use std::vec::Vec;
+
+    use super::backends::uncompressed::*;
+    use super::file::ImageFileSystem;
+    use core::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
 
-    pub(crate) const SB_MAGIC: u32 = 0xE0F5E1E2;
+    pub(crate) const SB_MAGIC: u32 = EROFS_SUPER_MAGIC_V1;
 
     pub(crate) type SimpleBufferedFileSystem =
         SuperblockInfo<SimpleInode, HashMap<Nid, SimpleInode>, ()>;
@@ -631,7 +789,7 @@ pub(crate) mod tests {
 
     pub(crate) fn load_fixtures_noxattr() -> impl Iterator<Item = TestFile> {
         let mut s = env!("CARGO_MANIFEST_DIR").to_string();
-        s.push_str(&"/tests/sample_noxattrs.img".to_string());
+        s.push_str("/tests/sample_noxattrs.img");
         return [TestFile {
             file: File::options()
                 .read(true)
@@ -655,7 +813,7 @@ pub(crate) mod tests {
             &*sbi.filesystem,
             &mut sbi.inodes,
             sbi.filesystem.superblock().root_nid as Nid,
-            "/texts/lipsum.txt",
+            b"/texts/lipsum.txt",
         )
         .unwrap();
         assert_eq!(inode.info().inode_type(), LIPSUM_TYPE);
@@ -678,7 +836,7 @@ pub(crate) mod tests {
             &*sbi.filesystem,
             &mut sbi.inodes,
             sbi.filesystem.superblock().root_nid as Nid,
-            "/images/inabukumori.jpg",
+            b"/images/inabukumori.jpg",
         )
         .unwrap();
         assert_eq!(inode.info().inode_type(), IMAGE_TYPE);
@@ -698,7 +856,7 @@ pub(crate) mod tests {
             &*sbi.filesystem,
             &mut sbi.inodes,
             sbi.filesystem.superblock().root_nid as Nid,
-            "/README.md",
+            b"/README.md",
         )
         .unwrap();
         assert_eq!(inode.info().inode_type(), README_TYPE);
@@ -725,7 +883,7 @@ pub(crate) mod tests {
             &*sbi.filesystem,
             &mut sbi.inodes,
             sbi.filesystem.superblock().root_nid as Nid,
-            "/README.md",
+            b"/README.md",
         )
         .unwrap();
 
@@ -765,12 +923,35 @@ pub(crate) mod tests {
             .is_err_and(|x| x == Errno::ENODATA));
     }
 
+    fn test_xattr_filter(sbi: &mut SimpleBufferedFileSystem) {
+        assert!(sbi.filesystem.superblock().has_xattr_filter());
+        let inode = lookup(
+            &*sbi.filesystem,
+            &mut sbi.inodes,
+            sbi.filesystem.superblock().root_nid as Nid,
+            b"/README.md",
+        )
+        .unwrap();
+        let entries = inode.xattrs_shared_entries();
+        assert!(entries.may_contain(1, b"sha512sum"));
+        assert!(entries.may_contain(1, b"sha512hmac"));
+        assert!(entries.may_contain(6, b"selinux"));
+        let absent = (0..64)
+            .map(|i| format!("absent{i}"))
+            .find(|name| !entries.may_contain(1, name.as_bytes()))
+            .unwrap();
+        assert!(sbi
+            .filesystem
+            .get_xattr(inode, 1, absent.as_bytes(), &mut None)
+            .is_err_and(|x| x == Errno::ENODATA));
+    }
+
     fn test_get_dir_xattr(sbi: &mut SimpleBufferedFileSystem) {
         let inode = lookup(
             &*sbi.filesystem,
             &mut sbi.inodes,
             sbi.filesystem.superblock().root_nid as Nid,
-            "/",
+            b"/",
         )
         .unwrap();
         assert!(sbi
@@ -785,7 +966,7 @@ pub(crate) mod tests {
             &*sbi.filesystem,
             &mut sbi.inodes,
             sbi.filesystem.superblock().root_nid as Nid,
-            "/README.md",
+            b"/README.md",
         )
         .unwrap();
         let length = sbi.filesystem.list_xattrs(inode, &mut result).unwrap();
@@ -793,6 +974,78 @@ pub(crate) mod tests {
             &result[..length],
             b"user.sha512sum\0user.sha512hmac\0security.selinux\0"
         );
+
+        assert_eq!(sbi.filesystem.list_xattrs(inode, &mut []).unwrap(), length);
+        assert_eq!(
+            list_xattrs(&*sbi.filesystem, inode, &mut result[..length]).unwrap(),
+            length
+        );
+        assert!(sbi
+            .filesystem
+            .list_xattrs(inode, &mut result[..length - 1])
+            .is_err_and(|x| x == Errno::ERANGE));
+        assert!(sbi
+            .filesystem
+            .list_xattrs(inode, &mut result[..4])
+            .is_err_and(|x| x == Errno::ERANGE));
+    }
+
+    fn test_xattrs_iter(sbi: &mut SimpleBufferedFileSystem) {
+        const README_SHA512_LITERAL: &[u8] = b"99fffc75aec028f417d9782fffed6c5d877a29ad1b16fc62bfeb168cdaf8db6db2bad1814904cd0fa18a2396c2c618041682a010601f4052b9895138d4ed6f16";
+        let inode = lookup(
+            &*sbi.filesystem,
+            &mut sbi.inodes,
+            sbi.filesystem.superblock().root_nid as Nid,
+            b"/README.md",
+        )
+        .unwrap();
+        let entries: Vec<XAttrEntry> = xattrs(&*sbi.filesystem, inode)
+            .unwrap()
+            .map(|x| x.unwrap())
+            .collect();
+        let names: Vec<&[u8]> = entries.iter().map(|x| x.full_name()).collect();
+        assert_eq!(
+            names,
+            [
+                &b"user.sha512sum"[..],
+                b"user.sha512hmac",
+                b"security.selinux"
+            ]
+        );
+        assert_eq!(entries[0].index(), 1);
+        assert_eq!(entries[0].name(), b"sha512sum");
+        assert_eq!(entries[0].value(), README_SHA512_LITERAL);
+        assert_eq!(entries[2].index(), 6);
+        assert!(get_acl(&*sbi.filesystem, inode, AclType::Access)
+            .unwrap()
+            .is_none());
+        for entry in entries.iter() {
+            let mut value = [0u8; 256];
+            let XAttrValue::Buffer(len) = sbi
+                .filesystem
+                .get_xattr(
+                    inode,
+                    entry.index() as u32,
+                    entry.name(),
+                    &mut Some(&mut value),
+                )
+                .unwrap()
+            else {
+                panic!();
+            };
+            assert_eq!(&value[..len], entry.value());
+        }
+    }
+
+    fn test_xattrs_iter_empty(sbi: &mut SimpleBufferedFileSystem) {
+        let inode = lookup(
+            &*sbi.filesystem,
+            &mut sbi.inodes,
+            sbi.filesystem.superblock().root_nid as Nid,
+            b"/README.md",
+        )
+        .unwrap();
+        assert_eq!(sbi.filesystem.xattrs(inode).unwrap().count(), 0);
     }
 
     fn test_list_xattr_empty(sbi: &mut SimpleBufferedFileSystem) {
@@ -801,11 +1054,12 @@ pub(crate) mod tests {
             &*sbi.filesystem,
             &mut sbi.inodes,
             sbi.filesystem.superblock().root_nid as Nid,
-            "/README.md",
+            b"/README.md",
         )
         .unwrap();
         let length = sbi.filesystem.list_xattrs(inode, &mut result).unwrap();
         assert_eq!(length, 0);
+        assert_eq!(sbi.filesystem.list_xattrs(inode, &mut []).unwrap(), 0);
     }
 
     fn test_get_xattr_empty(sbi: &mut SimpleBufferedFileSystem) {
@@ -813,7 +1067,7 @@ pub(crate) mod tests {
             &*sbi.filesystem,
             &mut sbi.inodes,
             sbi.filesystem.superblock().root_nid as Nid,
-            "/noxattr.txt",
+            b"/noxattr.txt",
         )
         .unwrap();
         assert!(sbi
@@ -822,17 +1076,199 @@ pub(crate) mod tests {
             .is_err_and(|x| x == Errno::ENODATA));
     }
 
+    fn test_read_dir(sbi: &mut SimpleBufferedFileSystem) {
+        let root_nid = sbi.filesystem.superblock().root_nid as Nid;
+        let root = sbi
+            .inodes
+            .iget(root_nid, sbi.filesystem.as_filesystem())
+            .unwrap();
+        let entries: Vec<DirEntry> = read_dir(&*sbi.filesystem, root)
+            .unwrap()
+            .collect::<PosixResult<_>>()
+            .unwrap();
+
+        let find = |name: &[u8]| entries.iter().find(|e| e.name() == name).unwrap();
+        assert_eq!(find(b".").nid(), root_nid);
+        assert_eq!(find(b".").file_type(), FileType::Directory);
+        assert_eq!(find(b"..").file_type(), FileType::Directory);
+        assert_eq!(find(b"texts").file_type(), FileType::Directory);
+        assert_eq!(find(b"README.md").file_type(), FileType::Regular);
+        assert!(read_dir(&*sbi.filesystem, root)
+            .unwrap()
+            .verify_file_types(true)
+            .all(|e| e.is_ok()));
+        for entry in entries.iter() {
+            assert!(!entry.name().contains(&0));
+            assert_eq!(
+                sbi.filesystem.find_nid(root, entry.name()).unwrap(),
+                Some(entry.nid())
+            );
+        }
+
+        // Resume right after every entry.
+        for (i, entry) in entries.iter().enumerate() {
+            let mut iter = read_dir(&*sbi.filesystem, root).unwrap();
+            iter.seek(entry.offset());
+            let rest: Vec<Nid> = iter.map(|e| e.unwrap().nid()).collect();
+            let expected: Vec<Nid> = entries[i + 1..].iter().map(|e| e.nid()).collect();
+            assert_eq!(rest, expected);
+        }
+
+        let readme = lookup(&*sbi.filesystem, &mut sbi.inodes, root_nid, b"/README.md").unwrap();
+        assert!(matches!(read_dir(&*sbi.filesystem, readme), Err(ENOTDIR)));
+    }
+
+    fn test_resolve(sbi: &mut SimpleBufferedFileSystem) {
+        let fs = &*sbi.filesystem;
+        let inodes = &mut sbi.inodes;
+        let root = fs.superblock().root_nid as Nid;
+        let follow = ResolveOptions::new();
+        let nofollow = ResolveOptions::new().follow_symlinks(false);
+        let mut resolve = |start, path: &[u8], options| resolve(fs, inodes, start, path, options);
+
+        let texts = resolve(root, b"texts", follow).unwrap();
+        assert_eq!(texts.parent(), root);
+        let images = resolve(root, b"/images/", follow).unwrap();
+        let lipsum = resolve(root, b"texts/lipsum.txt", follow).unwrap();
+        assert_eq!(lipsum.parent(), texts.nid());
+        let image = resolve(root, b"images/inabukumori.jpg", follow).unwrap();
+        assert_eq!(image.parent(), images.nid());
+
+        // Dots are resolved against the walked path.
+        assert_eq!(resolve(root, b"", follow).unwrap().nid(), root);
+        assert_eq!(resolve(root, b"/..", follow).unwrap().nid(), root);
+        assert_eq!(
+            resolve(root, b"./texts/../images/./inabukumori.jpg", follow).unwrap(),
+            image
+        );
+        assert_eq!(
+            resolve(texts.nid(), b"../README.md", follow)
+                .unwrap()
+                .parent(),
+            root
+        );
+
+        // Relative symbolic links.
+        assert_eq!(resolve(root, b"/lipsum.txt", follow).unwrap(), lipsum);
+        assert_eq!(resolve(root, b"blob.jpg", follow).unwrap(), image);
+        let link = resolve(root, b"blob.jpg", nofollow).unwrap();
+        assert_ne!(link.nid(), image.nid());
+        assert_eq!(link.parent(), root);
+        assert_eq!(
+            resolve(root, b"blob.jpg", follow.max_symlink_hops(0)),
+            Err(ELOOP)
+        );
+        assert_eq!(resolve(root, b"blob.jpg/", nofollow), Err(ENOTDIR));
+
+        assert_eq!(resolve(root, b"README.md/", follow), Err(ENOTDIR));
+        assert_eq!(resolve(root, b"README.md/..", follow), Err(ENOTDIR));
+        assert_eq!(resolve(root, b"missing", follow), Err(ENOENT));
+
+        let beneath = follow.beneath(true);
+        assert_eq!(
+            resolve(texts.nid(), b"lipsum.txt", beneath).unwrap(),
+            lipsum
+        );
+        assert_eq!(resolve(texts.nid(), b"../README.md", beneath), Err(EXDEV));
+        assert_eq!(resolve(texts.nid(), b"/README.md", beneath), Err(EXDEV));
+        assert_eq!(resolve(root, b"lipsum.txt", beneath).unwrap(), lipsum);
+    }
+
+    fn test_readlink(sbi: &mut SimpleBufferedFileSystem) {
+        const TARGET: &[u8] = b"images/inabukumori.jpg";
+        let root_nid = sbi.filesystem.superblock().root_nid as Nid;
+        let link = lookup(&*sbi.filesystem, &mut sbi.inodes, root_nid, b"/blob.jpg").unwrap();
+        assert_eq!(link.info().inode_type(), Type::Link);
+        assert_eq!(readlink(&*sbi.filesystem, link).unwrap(), TARGET);
+
+        let mut buffer = [0u8; EROFS_PATH_MAX];
+        let len = readlink_into(&*sbi.filesystem, link, &mut buffer).unwrap();
+        assert_eq!(&buffer[..len], TARGET);
+        let mut exact = [0u8; TARGET.len()];
+        assert_eq!(
+            readlink_into(&*sbi.filesystem, link, &mut exact),
+            Ok(TARGET.len())
+        );
+        let mut short = [0u8; TARGET.len() - 1];
+        assert_eq!(
+            readlink_into(&*sbi.filesystem, link, &mut short),
+            Err(ERANGE)
+        );
+
+        let readme = lookup(&*sbi.filesystem, &mut sbi.inodes, root_nid, b"/README.md").unwrap();
+        assert_eq!(readlink(&*sbi.filesystem, readme), Err(EINVAL));
+        assert_eq!(
+            readlink_into(&*sbi.filesystem, readme, &mut buffer),
+            Err(EINVAL)
+        );
+    }
+
+    fn test_read_at(sbi: &mut SimpleBufferedFileSystem) {
+        let root_nid = sbi.filesystem.superblock().root_nid as Nid;
+        for path in [
+            &b"/texts/lipsum.txt"[..],
+            b"/images/inabukumori.jpg",
+            b"/README.md",
+        ] {
+            let inode = lookup(&*sbi.filesystem, &mut sbi.inodes, root_nid, path).unwrap();
+            let mut expected = Vec::new();
+            for block in sbi.filesystem.mapped_iter(inode, 0).unwrap() {
+                expected.extend_from_slice(block.unwrap().content());
+            }
+            let size = inode.info().file_size() as usize;
+            assert_eq!(expected.len(), size);
+
+            let mut whole = vec![0u8; size + 100];
+            assert_eq!(sbi.filesystem.read_at(inode, 0, &mut whole), Ok(size));
+            assert_eq!(whole[..size], expected[..]);
+
+            let ranges = [(0, 1), (1, 511), (500, 1000), (4095, 2), (size - 1, 10)];
+            for (offset, len) in ranges.into_iter().filter(|(offset, _)| *offset < size) {
+                let mut buf = vec![0xffu8; len];
+                let read = sbi
+                    .filesystem
+                    .read_at(inode, offset as Off, &mut buf)
+                    .unwrap();
+                assert_eq!(read, len.min(size - offset));
+                assert_eq!(buf[..read], expected[offset..offset + read]);
+
+                // Range iterators stop at the end of the range whatever the backend.
+                let mut ranged = Vec::new();
+                let iter = sbi
+                    .filesystem
+                    .mapped_range_iter(inode, offset as Off, read as Off)
+                    .unwrap();
+                for block in iter {
+                    ranged.extend_from_slice(block.unwrap().content());
+                }
+                assert_eq!(ranged, buf[..read]);
+            }
+            assert_eq!(
+                sbi.filesystem.read_at(inode, size as Off, &mut whole),
+                Ok(0)
+            );
+            assert_eq!(sbi.filesystem.read_at(inode, 0, &mut []), Ok(0));
+        }
+    }
+
     pub(crate) fn test_filesystem(sbi: &mut SimpleBufferedFileSystem, xattrs_enabled: bool) {
         test_superblock_def(sbi);
         test_filesystem_ilookup1(sbi);
         test_filesystem_ilookup2(sbi);
         test_continous_iter(sbi);
+        test_read_dir(sbi);
+        test_resolve(sbi);
+        test_readlink(sbi);
+        test_read_at(sbi);
         if xattrs_enabled {
             test_get_file_xattr(sbi);
+            test_xattr_filter(sbi);
             test_get_dir_xattr(sbi);
             test_list_xattr(sbi);
+            test_xattrs_iter(sbi);
         } else {
             test_list_xattr_empty(sbi);
+            test_xattrs_iter_empty(sbi);
             test_get_xattr_empty(sbi);
         }
     }
@@ -841,4 +1277,528 @@ pub(crate) mod tests {
     fn test_superblock_size() {
         assert_eq!(core::mem::size_of::<SuperBlock>(), 128);
     }
+
+    /// A source over an in-memory image which counts the reads issued to it.
+    pub(crate) struct CountingSource {
+        pub(crate) data: Vec<u8>,
+        pub(crate) reads: Arc<AtomicUsize>,
+    }
+
+    impl Source for CountingSource {
+        fn fill(&self, data: &mut [u8], _device_id: i32, offset: Off) -> PosixResult<u64> {
+            self.reads.fetch_add(1, Ordering::Relaxed);
+            let start = (offset as usize).min(self.data.len());
+            let len = data.len().min(self.data.len() - start);
+            data[..len].copy_from_slice(&self.data[start..start + len]);
+            Ok(len as u64)
+        }
+    }
+
+    impl CountingSource {
+        pub(crate) fn new(data: Vec<u8>) -> Self {
+            Self {
+                data,
+                reads: Arc::new(AtomicUsize::new(0)),
+            }
+        }
+    }
+
+    /// Build a minimal uncompressed image with 4KiB blocks whose root directory (nid 0) holds
+    /// `entries` in the FlatPlain layout, packed into blocks the same way mkfs.erofs does.
+    pub(crate) fn build_dir_image(entries: &[(&[u8], Nid, u8)]) -> Vec<u8> {
+        build_dir_image_with(0, entries)
+    }
+
+    /// Same as build_dir_image but with directory blocks of `4096 << dirblkbits` bytes.
+    pub(crate) fn build_dir_image_with(dirblkbits: u8, entries: &[(&[u8], Nid, u8)]) -> Vec<u8> {
+        const BLKSZ: usize = 4096;
+        let dirblksz = BLKSZ << dirblkbits;
+        let mut entries = entries.to_vec();
+        entries.sort_by(|a, b| a.0.cmp(b.0));
+
+        let mut blocks: Vec<Vec<u8>> = Vec::new();
+        let mut rest = &entries[..];
+        while !rest.is_empty() {
+            let mut count = 0;
+            let mut used = 0;
+            while count < rest.len() && used + 12 + rest[count].0.len() <= dirblksz {
+                used += 12 + rest[count].0.len();
+                count += 1;
+            }
+            let mut block = vec![0u8; dirblksz];
+            let mut nameoff = count * 12;
+            for (i, (name, nid, file_type)) in rest[..count].iter().enumerate() {
+                block[i * 12..i * 12 + 8].copy_from_slice(&nid.to_le_bytes());
+                block[i * 12 + 8..i * 12 + 10].copy_from_slice(&(nameoff as u16).to_le_bytes());
+                block[i * 12 + 10] = *file_type;
+                block[nameoff..nameoff + name.len()].copy_from_slice(name);
+                nameoff += name.len();
+            }
+            blocks.push(block);
+            rest = &rest[count..];
+        }
+
+        let mut image = vec![0u8; BLKSZ * 2];
+        let sb = &mut image[EROFS_SUPER_OFFSET as usize..];
+        sb[0..4].copy_from_slice(&SB_MAGIC.to_le_bytes());
+        sb[12] = 12;
+        sb[90] = dirblkbits;
+        sb[40..44].copy_from_slice(&1u32.to_le_bytes());
+        let root = &mut image[BLKSZ..BLKSZ + 32];
+        root[4..6].copy_from_slice(&0o40755u16.to_le_bytes());
+        root[6..8].copy_from_slice(&2u16.to_le_bytes());
+        root[8..12].copy_from_slice(&((blocks.len() * dirblksz) as u32).to_le_bytes());
+        root[16..20].copy_from_slice(&2u32.to_le_bytes());
+        for block in blocks {
+            image.extend_from_slice(&block);
+        }
+        image
+    }
+
+    /// Append a FlatPlain symlink inode at `nid` of a [`build_dir_image`] image whose target
+    /// lives in a block of its own.
+    pub(crate) fn add_symlink(image: &mut Vec<u8>, nid: Nid, target: &[u8]) {
+        const BLKSZ: usize = 4096;
+        let blkaddr = (image.len() / BLKSZ) as u32;
+        let pos = BLKSZ + nid as usize * 32;
+        let inode = &mut image[pos..pos + 32];
+        inode[4..6].copy_from_slice(&0o120777u16.to_le_bytes());
+        inode[6..8].copy_from_slice(&1u16.to_le_bytes());
+        inode[8..12].copy_from_slice(&(target.len() as u32).to_le_bytes());
+        inode[16..20].copy_from_slice(&blkaddr.to_le_bytes());
+        let mut block = [0u8; BLKSZ];
+        block[..target.len()].copy_from_slice(target);
+        image.extend_from_slice(&block);
+    }
+
+    fn root_inode(filesystem: &dyn FileSystem<SimpleInode>) -> SimpleInode {
+        let info = filesystem.read_inode_info(0).unwrap();
+        SimpleInode::new(
+            filesystem.superblock(),
+            info,
+            0,
+            filesystem
+                .read_inode_xattrs_shared_entries(0, &info)
+                .unwrap(),
+        )
+    }
+
+    #[test]
+    fn test_non_utf8_names() {
+        let image = build_dir_image(&[(b".", 0, 2), (b"..", 0, 2), (b"\xff\xfe", 0, 2)]);
+        let fs =
+            ImageFileSystem::try_new(UncompressedBackend::new(CountingSource::new(image))).unwrap();
+        let filesystem: &dyn FileSystem<SimpleInode> = &fs;
+        let root = root_inode(filesystem);
+        assert_eq!(filesystem.find_nid(&root, b"\xff\xfe").unwrap(), Some(0));
+        assert_eq!(filesystem.find_nid(&root, b"\xff").unwrap(), None);
+
+        let mut inodes: HashMap<Nid, SimpleInode> = HashMap::new();
+        let inode = lookup(filesystem, &mut inodes, 0, b"/\xff\xfe/./\xff\xfe").unwrap();
+        assert_eq!(inode.nid(), 0);
+        assert_eq!(
+            dir_lookup(filesystem, &mut inodes, &root, b"\xfe").err(),
+            Some(ENOENT)
+        );
+    }
+
+    #[test]
+    fn test_read_at_holes() {
+        const BLKSZ: usize = 4096;
+        let mut image = build_dir_image(&[(b".", 0, 2), (b"..", 0, 2), (b"sparse", 1, 1)]);
+        let data = image.len() / BLKSZ;
+        // A chunk based file of one block chunks, the middle one unallocated.
+        let inode = &mut image[BLKSZ + 32..BLKSZ + 64];
+        inode[0..2].copy_from_slice(&(4u16 << 1).to_le_bytes());
+        inode[4..6].copy_from_slice(&0o100644u16.to_le_bytes());
+        inode[6..8].copy_from_slice(&1u16.to_le_bytes());
+        inode[8..12].copy_from_slice(&((BLKSZ * 3 - 100) as u32).to_le_bytes());
+        let chunks = &mut image[BLKSZ + 64..BLKSZ + 76];
+        chunks[0..4].copy_from_slice(&(data as u32).to_le_bytes());
+        chunks[4..8].copy_from_slice(&u32::MAX.to_le_bytes());
+        chunks[8..12].copy_from_slice(&(data as u32 + 1).to_le_bytes());
+        image.extend(std::iter::repeat(1u8).take(BLKSZ));
+        image.extend(std::iter::repeat(3u8).take(BLKSZ));
+
+        let fs =
+            ImageFileSystem::try_new(UncompressedBackend::new(CountingSource::new(image))).unwrap();
+        let filesystem: &dyn FileSystem<SimpleInode> = &fs;
+        let mut inodes: HashMap<Nid, SimpleInode> = HashMap::new();
+        let sparse = lookup(filesystem, &mut inodes, 0, b"sparse").unwrap();
+        let size = BLKSZ * 3 - 100;
+
+        let mut expected = vec![1u8; BLKSZ];
+        expected.extend(std::iter::repeat(0u8).take(BLKSZ));
+        expected.extend(std::iter::repeat(3u8).take(BLKSZ - 100));
+        let mut buf = vec![0xffu8; BLKSZ * 4];
+        assert_eq!(filesystem.read_at(sparse, 0, &mut buf), Ok(size));
+        assert_eq!(buf[..size], expected[..]);
+        let mut buf = vec![0xffu8; 200];
+        assert_eq!(
+            filesystem.read_at(sparse, BLKSZ as Off * 2 - 100, &mut buf),
+            Ok(200)
+        );
+        assert_eq!(buf[..], expected[BLKSZ * 2 - 100..BLKSZ * 2 + 100]);
+
+        let mut mapped = Vec::new();
+        for block in filesystem.mapped_iter(sparse, 0).unwrap() {
+            mapped.extend_from_slice(block.unwrap().content());
+        }
+        assert_eq!(mapped, expected);
+    }
+
+    #[test]
+    fn test_compressed_packed_inode_prefixes() {
+        const BLKSZ: usize = 4096;
+        let mut image = build_dir_image(&[(b".", 0, 2), (b"..", 0, 2), (b"file", 1, 1)]);
+        // Long prefixes are said to live in the packed inode (nid 4), which is compressed.
+        let sb = &mut image[EROFS_SUPER_OFFSET as usize..];
+        let features = EROFS_FEATURE_INCOMPAT_FRAGMENTS | EROFS_FEATURE_INCOMPAT_XATTR_PREFIXES;
+        sb[80..84].copy_from_slice(&features.to_le_bytes());
+        sb[91] = 1;
+        sb[96..104].copy_from_slice(&4u64.to_le_bytes());
+        let packed = &mut image[BLKSZ + 128..BLKSZ + 160];
+        packed[0..2].copy_from_slice(&(1u16 << 1).to_le_bytes());
+        packed[4..6].copy_from_slice(&0o100644u16.to_le_bytes());
+        packed[6..8].copy_from_slice(&1u16.to_le_bytes());
+        packed[8..12].copy_from_slice(&(BLKSZ as u32).to_le_bytes());
+
+        // An empty extended inode with an inline "user.a" xattr and one using a long prefix.
+        let inode = &mut image[BLKSZ + 32..BLKSZ + 96];
+        inode[0..2].copy_from_slice(&1u16.to_le_bytes());
+        inode[2..4].copy_from_slice(&5u16.to_le_bytes());
+        inode[4..6].copy_from_slice(&0o100644u16.to_le_bytes());
+        inode[44..48].copy_from_slice(&1u32.to_le_bytes());
+        let xattrs = &mut image[BLKSZ + 96..BLKSZ + 124];
+        xattrs[12..20].copy_from_slice(&[1, 1, 1, 0, b'a', b'1', 0, 0]);
+        xattrs[20..28].copy_from_slice(&[1, 0x80, 1, 0, b'x', b'2', 0, 0]);
+
+        let fs =
+            ImageFileSystem::try_new(UncompressedBackend::new(CountingSource::new(image))).unwrap();
+        let filesystem: &dyn FileSystem<SimpleInode> = &fs;
+        assert!(filesystem.xattr_infixes().is_empty());
+        let mut inodes: HashMap<Nid, SimpleInode> = HashMap::new();
+        let file = lookup(filesystem, &mut inodes, 0, b"file").unwrap();
+        let mut result = [0u8; 64];
+        let length = filesystem.list_xattrs(file, &mut result).unwrap();
+        assert_eq!(&result[..length], b"user.a\0");
+        assert!(matches!(
+            filesystem.get_xattr(file, 1, b"a", &mut None),
+            Ok(XAttrValue::Vec(v)) if v == b"1"
+        ));
+        let iter = filesystem.xattrs(file).unwrap();
+        assert_eq!(iter.filter(|x| x.is_ok()).count(), 1);
+    }
+
+    /// Records the length of every read so that tests can check how reads are batched.
+    struct RecordingBackend {
+        data: Vec<u8>,
+        fills: Arc<std::sync::Mutex<Vec<usize>>>,
+    }
+
+    impl Backend for RecordingBackend {
+        fn fill(&self, data: &mut [u8], _device_id: i32, offset: Off) -> PosixResult<u64> {
+            self.fills.lock().unwrap().push(data.len());
+            self.data.fill(data, 0, offset)
+        }
+    }
+
+    impl FileBackend for RecordingBackend {}
+
+    #[test]
+    fn test_coalesced_reads() {
+        const BLKSZ: usize = 4096;
+        let mut image = build_dir_image(&[(b".", 0, 2), (b"..", 0, 2), (b"file", 1, 1)]);
+        let data = image.len() / BLKSZ;
+        // Three contiguous chunks, two unallocated ones and a last chunk elsewhere.
+        let layout = [0, 1, 2, u32::MAX, u32::MAX, 4];
+        let size = BLKSZ * layout.len() - 100;
+        let inode = &mut image[BLKSZ + 32..BLKSZ + 64];
+        inode[0..2].copy_from_slice(&(4u16 << 1).to_le_bytes());
+        inode[4..6].copy_from_slice(&0o100644u16.to_le_bytes());
+        inode[6..8].copy_from_slice(&1u16.to_le_bytes());
+        inode[8..12].copy_from_slice(&(size as u32).to_le_bytes());
+        for (i, blk) in layout.iter().enumerate() {
+            let blkaddr = blk.checked_add(data as u32).unwrap_or(u32::MAX);
+            let pos = BLKSZ + 64 + i * 4;
+            image[pos..pos + 4].copy_from_slice(&blkaddr.to_le_bytes());
+        }
+        for i in 0..5u8 {
+            image.extend(std::iter::repeat(i + 1).take(BLKSZ));
+        }
+        let mut expected = Vec::new();
+        for blk in layout {
+            let value = if blk == u32::MAX { 0 } else { blk as u8 + 1 };
+            expected.extend(std::iter::repeat(value).take(BLKSZ));
+        }
+        expected.truncate(size);
+
+        let fills = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let fs = ImageFileSystem::try_new(RecordingBackend {
+            data: image,
+            fills: fills.clone(),
+        })
+        .unwrap();
+        let filesystem: &dyn FileSystem<SimpleInode> = &fs;
+        let mut inodes: HashMap<Nid, SimpleInode> = HashMap::new();
+        let file = lookup(filesystem, &mut inodes, 0, b"file").unwrap();
+        let data_reads = || -> Vec<usize> {
+            let mut fills = fills.lock().unwrap();
+            let reads = fills.iter().copied().filter(|len| *len > 4).collect();
+            fills.clear();
+            reads
+        };
+        data_reads();
+
+        let mut buf = vec![0xffu8; size + 10];
+        assert_eq!(filesystem.read_at(file, 0, &mut buf), Ok(size));
+        assert_eq!(buf[..size], expected[..]);
+        assert_eq!(data_reads(), [3 * BLKSZ, BLKSZ - 100]);
+        // Reads never go past the requested range.
+        let mut buf = vec![0u8; BLKSZ + 10];
+        assert_eq!(filesystem.read_at(file, 100, &mut buf), Ok(BLKSZ + 10));
+        assert_eq!(buf[..], expected[100..BLKSZ + 110]);
+        assert_eq!(data_reads(), [BLKSZ + 10]);
+
+        let mut lens = Vec::new();
+        let mut mapped = Vec::new();
+        for block in filesystem.mapped_range_iter(file, 10, size as Off).unwrap() {
+            let block = block.unwrap();
+            lens.push(block.content().len());
+            mapped.extend_from_slice(block.content());
+        }
+        assert_eq!(mapped, expected[10..]);
+        assert_eq!(lens, [3 * BLKSZ - 10, 2 * BLKSZ, BLKSZ - 100]);
+        // The plain iterator still yields single blocks.
+        assert_eq!(
+            filesystem.mapped_iter(file, 0).unwrap().count(),
+            layout.len()
+        );
+    }
+
+    #[test]
+    fn test_read_dir_file_types() {
+        // Every entry refers to the root directory itself.
+        let image = build_dir_image(&[(b".", 0, 2), (b"..", 0, 2), (b"a", 0, 0), (b"b", 0, 1)]);
+        let fs =
+            ImageFileSystem::try_new(UncompressedBackend::new(CountingSource::new(image))).unwrap();
+        let filesystem: &dyn FileSystem<SimpleInode> = &fs;
+        let root = root_inode(filesystem);
+
+        let types: Vec<FileType> = read_dir(filesystem, &root)
+            .unwrap()
+            .map(|e| e.unwrap().file_type())
+            .collect();
+        assert_eq!(
+            types,
+            [
+                FileType::Directory,
+                FileType::Directory,
+                FileType::Unknown,
+                FileType::Regular
+            ]
+        );
+        let verified: Vec<PosixResult<DirEntry>> = read_dir(filesystem, &root)
+            .unwrap()
+            .verify_file_types(true)
+            .collect();
+        assert_eq!(verified.len(), 4);
+        assert!(verified[..3].iter().all(|e| e.is_ok()));
+        assert!(matches!(verified[3], Err(EUCLEAN)));
+
+        let image = build_dir_image(&[(b".", 0, 2), (b"bad", 0, 42)]);
+        let fs =
+            ImageFileSystem::try_new(UncompressedBackend::new(CountingSource::new(image))).unwrap();
+        let filesystem: &dyn FileSystem<SimpleInode> = &fs;
+        let root = root_inode(filesystem);
+        let mut iter = read_dir(filesystem, &root).unwrap();
+        assert!(iter.next().unwrap().is_ok());
+        assert!(matches!(iter.next(), Some(Err(EUCLEAN))));
+        assert!(iter.next().is_none());
+    }
+
+    #[test]
+    fn test_dirblkbits() {
+        const COUNT: u64 = 3000;
+        let names: Vec<std::string::String> = (0..COUNT).map(|i| format!("entry{i:05}")).collect();
+        let entries: Vec<(&[u8], Nid, u8)> = names
+            .iter()
+            .enumerate()
+            .map(|(i, name)| (name.as_bytes(), 100 + i as Nid, 1))
+            .collect();
+        let image = build_dir_image_with(2, &entries);
+        let fs =
+            ImageFileSystem::try_new(UncompressedBackend::new(CountingSource::new(image))).unwrap();
+        let filesystem: &dyn FileSystem<SimpleInode> = &fs;
+        let root = root_inode(filesystem);
+        assert_eq!(filesystem.superblock().dirblksz(), 16384);
+        assert!(root.info().file_size() > 16384);
+
+        let listed: Vec<DirEntry> = read_dir(filesystem, &root)
+            .unwrap()
+            .collect::<PosixResult<_>>()
+            .unwrap();
+        assert_eq!(listed.len(), COUNT as usize);
+        for (i, entry) in listed.iter().enumerate() {
+            assert_eq!(entry.name(), names[i].as_bytes());
+            assert_eq!(entry.nid(), 100 + i as Nid);
+        }
+
+        let mut filled = 0;
+        filesystem
+            .fill_dentries(&root, 0, 0, &mut |dirent, _| {
+                assert_eq!({ dirent.desc().nid }, 100 + filled);
+                filled += 1;
+                false
+            })
+            .unwrap();
+        assert_eq!(filled, COUNT);
+
+        for i in (0..COUNT).step_by(7) {
+            assert_eq!(
+                filesystem
+                    .find_nid(&root, names[i as usize].as_bytes())
+                    .unwrap(),
+                Some(100 + i)
+            );
+        }
+        assert_eq!(filesystem.find_nid(&root, b"entry99999").unwrap(), None);
+    }
+
+    #[test]
+    fn test_find_nid_large_dir() {
+        const COUNT: u64 = 100_000;
+        let names: Vec<std::string::String> = (0..COUNT).map(|i| format!("file{i:06}")).collect();
+        let entries: Vec<(&[u8], Nid, u8)> = names
+            .iter()
+            .enumerate()
+            .map(|(i, name)| (name.as_bytes(), 100 + i as Nid, 1))
+            .collect();
+        let image = build_dir_image(&entries);
+        let nblocks = (image.len() / 4096 - 2) as u32;
+        let source = CountingSource::new(image);
+        let reads = source.reads.clone();
+        let fs = ImageFileSystem::try_new(UncompressedBackend::new(source)).unwrap();
+        let filesystem: &dyn FileSystem<SimpleInode> = &fs;
+        let root = root_inode(filesystem);
+
+        let bound = nblocks.ilog2() as usize + 2;
+        for i in [0, 1, 4242, COUNT / 2, COUNT - 2, COUNT - 1] {
+            let before = reads.load(Ordering::Relaxed);
+            let nid = filesystem
+                .find_nid(&root, format!("file{i:06}").as_bytes())
+                .unwrap();
+            assert_eq!(nid, Some(100 + i));
+            assert!(reads.load(Ordering::Relaxed) - before <= bound);
+        }
+        for name in [&b"a"[..], b"file", b"file0424205", b"file099999x", b"zzz"] {
+            let before = reads.load(Ordering::Relaxed);
+            assert_eq!(filesystem.find_nid(&root, name).unwrap(), None);
+            assert!(reads.load(Ordering::Relaxed) - before <= bound);
+        }
+
+        let names_read: Vec<Vec<u8>> = read_dir(filesystem, &root)
+            .unwrap()
+            .map(|e| e.unwrap().name().to_vec())
+            .collect();
+        assert_eq!(names_read.len(), COUNT as usize);
+        assert!(names_read
+            .iter()
+            .zip(names.iter())
+            .all(|(a, b)| a == b.as_bytes()));
+
+        // A linear scan would read every directory block.
+        let before = reads.load(Ordering::Relaxed);
+        for i in (0..COUNT).step_by(97) {
+            let nid = filesystem
+                .find_nid(&root, format!("file{i:06}").as_bytes())
+                .unwrap();
+            assert_eq!(nid, Some(100 + i));
+        }
+        let lookups = COUNT.div_ceil(97) as usize;
+        let total = reads.load(Ordering::Relaxed) - before;
+        assert!(total <= lookups * bound);
+    }
+
+    /// Exercise every parsing path on an untrusted image. Errors are fine, panics are not.
+    pub(crate) fn exercise_image(image: Vec<u8>) {
+        let Ok(fs) = ImageFileSystem::try_new(UncompressedBackend::new(CountingSource::new(image)))
+        else {
+            return;
+        };
+        let filesystem: &dyn FileSystem<SimpleInode> = &fs;
+        let sb = filesystem.superblock();
+        let mut pending = vec![sb.root_nid as Nid];
+        let mut visited = Vec::new();
+        while let Some(nid) = pending.pop() {
+            if visited.contains(&nid) || visited.len() >= 64 {
+                continue;
+            }
+            visited.push(nid);
+            let Ok(info) = filesystem.read_inode_info(nid) else {
+                continue;
+            };
+            let Ok(shared) = filesystem.read_inode_xattrs_shared_entries(nid, &info) else {
+                continue;
+            };
+            let inode = SimpleInode::new(sb, info, nid, shared);
+
+            let mut buffer = [0u8; 512];
+            let _ = filesystem.list_xattrs(&inode, &mut []);
+            let _ = filesystem.list_xattrs(&inode, &mut buffer);
+            let _ = filesystem.get_xattr(&inode, 1, b"sha512sum", &mut None);
+            let _ = filesystem.get_xattr(&inode, 1, b"sha512sum", &mut Some(&mut buffer));
+            if let Ok(iter) = filesystem.xattrs(&inode) {
+                iter.take(64).for_each(drop);
+            }
+
+            if let Ok(iter) = filesystem.mapped_iter(&inode, 0) {
+                iter.take(8).for_each(drop);
+            }
+            let _ = filesystem.map(&inode, info.file_size() / 2);
+            let _ = filesystem.read_at(&inode, info.file_size() / 3, &mut buffer);
+
+            if let Ok(iter) = filesystem.read_dir(&inode) {
+                for entry in iter.verify_file_types(true).take(64).flatten() {
+                    pending.push(entry.nid());
+                    let _ = filesystem.find_nid(&inode, entry.name());
+                }
+            }
+            let mut count = 0;
+            let _ = filesystem.fill_dentries(&inode, 0, 0, &mut |_, _| {
+                count += 1;
+                count > 64
+            });
+        }
+    }
+
+    #[test]
+    fn test_corrupted_images() {
+        // xorshift64, so that failures are reproducible.
+        let mut state: u64 = 0x2545_F491_4F6C_DD1D;
+        let mut next = move || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state
+        };
+        for testcase in load_fixtures_full().chain(load_fixtures_noxattr()) {
+            let mut original = Vec::new();
+            std::io::Read::read_to_end(&mut &testcase.file, &mut original).unwrap();
+            for _ in 0..200 {
+                let mut image = original.clone();
+                // Most of the metadata lives in the first few blocks.
+                let range = if next() % 2 == 0 { 8192 } else { image.len() };
+                for _ in 0..1 + next() % 16 {
+                    let pos = (next() as usize) % range.min(image.len());
+                    image[pos] = next() as u8;
+                }
+                exercise_image(image);
+            }
+            // Truncated images.
+            for len in [0, 1024, 1100, 4096, original.len() / 2] {
+                exercise_image(original[..len.min(original.len())].to_vec());
+            }
+        }
+    }
 }
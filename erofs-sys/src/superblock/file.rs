@@ -1,19 +1,26 @@
 // Copyright 2024 Yiyang Wu
 // SPDX-License-Identifier: MIT or GPL-2.0-or-later
 
+use super::data::pool::*;
 use super::data::raw_iters::temp_iter::*;
 use super::operations::*;
 use super::*;
 
+/// The largest single read of file data, large enough to approach sequential disk throughput
+/// while keeping temporary buffers bounded.
+pub const EROFS_MAX_IO_LEN: Off = 1 << 20;
+
 /// Only support standard file/device io. Not a continguous region of memory.
 pub struct ImageFileSystem<B>
 where
     B: FileBackend,
 {
     backend: B,
+    pool: BufferPool,
     infixes: Vec<XAttrInfix>,
     sb: SuperBlock,
     device_info: DeviceInfo,
+    xattr_cache: Option<XAttrSharedCache>,
 }
 
 impl<I, B> FileSystem<I> for ImageFileSystem<B>
@@ -36,10 +43,30 @@ where
         heap_alloc(TempBufferMapIter::new(
             &self.sb,
             &self.backend,
+            &self.pool,
             MapIter::new(self, inode, offset),
         ))
         .map(|v| v as Box<dyn BufferMapIter<'a> + 'b>)
     }
+    fn max_io_len(&self) -> Option<Off> {
+        Some(EROFS_MAX_IO_LEN)
+    }
+    fn mapped_range_iter<'b, 'a: 'b>(
+        &'a self,
+        inode: &'b I,
+        offset: Off,
+        len: Off,
+    ) -> PosixResult<Box<dyn BufferMapIter<'a> + 'b>> {
+        heap_alloc(TempBufferMapIter::new(
+            &self.sb,
+            &self.backend,
+            &self.pool,
+            MapIter::new(self, inode, offset)
+                .until(len)
+                .coalesce(Some(EROFS_MAX_IO_LEN)),
+        ))
+        .map(|v| v as Box<dyn BufferMapIter<'a> + 'b>)
+    }
     fn continuous_iter<'a>(
         &'a self,
         offset: Off,
@@ -48,6 +75,7 @@ where
         heap_alloc(ContinuousTempBufferIter::new(
             &self.sb,
             &self.backend,
+            &self.pool,
             offset,
             len,
         ))
@@ -59,6 +87,9 @@ where
     fn device_info(&self) -> &DeviceInfo {
         &self.device_info
     }
+    fn xattr_shared_cache(&self) -> Option<&XAttrSharedCache> {
+        self.xattr_cache.as_ref()
+    }
     fn as_filesystem(&self) -> &dyn FileSystem<I> {
         self
     }
@@ -73,24 +104,31 @@ where
         let mut buf = SUPERBLOCK_EMPTY_BUF;
         backend.fill(&mut buf, 0, EROFS_SUPER_OFFSET)?;
         let sb: SuperBlock = buf.into();
-        let infixes = get_xattr_infixes(&mut ContinuousTempBufferIter::new(
-            &sb,
-            &backend,
-            sb.xattr_prefix_start as Off,
-            sb.xattr_prefix_count as Off * 4,
-        ))?;
+        sb.validate()?;
+        let pool = BufferPool::new();
         let device_info = get_device_infos(&mut ContinuousTempBufferIter::new(
             &sb,
             &backend,
-            sb.devt_slotoff as Off * 128,
-            sb.extra_devices as Off * 128,
+            &pool,
+            sb.devt_slotoff as u16 as Off * 128,
+            sb.extra_devices as u16 as Off * 128,
         ))?;
-        Ok(Self {
+        let mut fs = Self {
             backend,
+            pool,
             sb,
-            infixes,
+            infixes: Vec::new(),
             device_info,
-        })
+            xattr_cache: None,
+        };
+        fs.infixes = get_xattr_infixes(&fs)?;
+        Ok(fs)
+    }
+
+    /// Cache at most `capacity` decoded shared xattr entries so that inodes sharing the same
+    /// entries don't have to read them from the backend again.
+    pub fn enable_xattr_cache(&mut self, capacity: usize) {
+        self.xattr_cache = Some(XAttrSharedCache::new(capacity));
     }
 }
 
@@ -104,17 +142,6 @@ mod tests {
 
     use std::boxed::Box;
     use std::collections::HashMap;
-    use std::fs::File;
-    use std::os::unix::fs::FileExt;
-
-    impl Source for File {
-        fn fill(&self, data: &mut [u8], _device_id: i32, offset: Off) -> PosixResult<u64> {
-            self.read_at(data, offset)
-                .map_or(Err(ERANGE), |size| Ok(size as u64))
-        }
-    }
-
-    impl FileSource for File {}
 
     #[test]
     fn test_uncompressed_img_filesystem() {
@@ -139,4 +166,17 @@ mod tests {
             test_filesystem(&mut sbi, testcase.xattrs);
         }
     }
+
+    #[test]
+    fn test_uncompressed_img_filesystem_xattr_cache() {
+        for testcase in load_fixtures_full() {
+            let mut fs = ImageFileSystem::try_new(UncompressedBackend::new(testcase.file)).unwrap();
+            fs.enable_xattr_cache(1);
+            let mut sbi: SimpleBufferedFileSystem =
+                SuperblockInfo::new(Box::new(fs), HashMap::new(), ());
+            // Run twice so that the second pass is served from the cache.
+            test_filesystem(&mut sbi, testcase.xattrs);
+            test_filesystem(&mut sbi, testcase.xattrs);
+        }
+    }
 }
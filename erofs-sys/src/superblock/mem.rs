@@ -18,6 +18,7 @@ where
     sb: SuperBlock,
     infixes: Vec<XAttrInfix>,
     device_info: DeviceInfo,
+    xattr_cache: Option<XAttrSharedCache>,
 }
 
 impl<I, T> FileSystem<I> for MemFileSystem<T>
@@ -48,6 +49,20 @@ where
         ))
         .map(|v| v as Box<dyn BufferMapIter<'a> + 'b>)
     }
+    fn mapped_range_iter<'b, 'a: 'b>(
+        &'a self,
+        inode: &'b I,
+        offset: Off,
+        len: Off,
+    ) -> PosixResult<Box<dyn BufferMapIter<'a> + 'b>> {
+        // Borrowed buffers can't span pages, so there is nothing to coalesce.
+        heap_alloc(RefMapIter::new(
+            &self.sb,
+            &self.backend,
+            MapIter::new(self, inode, offset).until(len),
+        ))
+        .map(|v| v as Box<dyn BufferMapIter<'a> + 'b>)
+    }
     fn continuous_iter<'a>(
         &'a self,
         offset: Off,
@@ -62,6 +77,9 @@ where
     fn device_info(&self) -> &DeviceInfo {
         &self.device_info
     }
+    fn xattr_shared_cache(&self) -> Option<&XAttrSharedCache> {
+        self.xattr_cache.as_ref()
+    }
 }
 
 impl<T> MemFileSystem<T>
@@ -73,24 +91,28 @@ where
         let mut buf = SUPERBLOCK_EMPTY_BUF;
         backend.fill(&mut buf, 0, EROFS_SUPER_OFFSET)?;
         let sb: SuperBlock = buf.into();
-        let infixes = get_xattr_infixes(&mut ContinuousRefIter::new(
-            &sb,
-            &backend,
-            sb.xattr_prefix_start as Off,
-            sb.xattr_prefix_count as Off * 4,
-        ))?;
+        sb.validate()?;
         let device_info = get_device_infos(&mut ContinuousRefIter::new(
             &sb,
             &backend,
-            sb.devt_slotoff as Off * 128,
-            sb.extra_devices as Off * 128,
+            sb.devt_slotoff as u16 as Off * 128,
+            sb.extra_devices as u16 as Off * 128,
         ))?;
-        Ok(Self {
+        let mut fs = Self {
             backend,
             sb,
-            infixes,
+            infixes: Vec::new(),
             device_info,
-        })
+            xattr_cache: None,
+        };
+        fs.infixes = get_xattr_infixes(&fs)?;
+        Ok(fs)
+    }
+
+    /// Cache at most `capacity` decoded shared xattr entries so that inodes sharing the same
+    /// entries don't have to read them from the backend again.
+    pub fn enable_xattr_cache(&mut self, capacity: usize) {
+        self.xattr_cache = Some(XAttrSharedCache::new(capacity));
     }
 }
 
@@ -106,27 +128,6 @@ mod tests {
     use memmap2::MmapMut;
     use std::collections::HashMap;
 
-    // Impl MmapMut to simulate a in-memory image/filesystem
-    impl Source for MmapMut {
-        fn fill(&self, data: &mut [u8], _device_id: i32, offset: Off) -> PosixResult<u64> {
-            self.as_buf(_device_id, offset, data.len() as u64)
-                .map(|buf| {
-                    let len = buf.content().len();
-                    data[..len].clone_from_slice(buf.content());
-                    len as Off
-                })
-        }
-    }
-
-    impl<'a> PageSource<'a> for MmapMut {
-        fn as_buf(&'a self, _device_id: i32, offset: Off, len: Off) -> PosixResult<RefBuffer<'a>> {
-            let maxsize = self.len() as Off;
-            let rlen = len.min(self.len() as u64 - offset);
-            let buf = &self[offset as usize..maxsize.min(offset + rlen) as usize];
-            Ok(RefBuffer::new(buf, 0, rlen as usize, |_| {}))
-        }
-    }
-
     #[test]
     fn test_uncompressed_mmap_filesystem() {
         for testcase in load_fixtures_noxattr() {
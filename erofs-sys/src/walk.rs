@@ -0,0 +1,593 @@
+// Copyright 2024 Yiyang Wu
+// SPDX-License-Identifier: MIT or GPL-2.0-or-later
+
+use alloc::collections::BTreeSet;
+use alloc::vec::Vec;
+
+use super::alloc_helper::*;
+use super::errnos::*;
+use super::inode::*;
+use super::superblock::*;
+use super::*;
+
+/// The order in which [`walk`](super::operations::walk) visits the tree.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WalkOrder {
+    /// Every directory is followed by its whole subtree, in directory order.
+    DepthFirst,
+    /// All entries of one depth are visited before any entry of the next one.
+    BreadthFirst,
+}
+
+/// What the visitor of [`walk`](super::operations::walk) wants to happen next.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WalkControl {
+    /// Keep going, descending into the entry if it is a directory.
+    Continue,
+    /// Don't descend into the entry. No effect on anything but directories.
+    Prune,
+    /// Stop the walk altogether.
+    Stop,
+}
+
+/// An inode visited by [`walk`](super::operations::walk).
+pub struct WalkEntry<'a, I>
+where
+    I: Inode,
+{
+    path: &'a [u8],
+    inode: &'a I,
+    parent: Nid,
+    depth: usize,
+    hardlink: bool,
+}
+
+impl<'a, I> WalkEntry<'a, I>
+where
+    I: Inode,
+{
+    /// The path of the entry, `/` for the starting directory.
+    pub fn path(&self) -> &'a [u8] {
+        self.path
+    }
+    /// The last component of the path, empty for the starting directory.
+    pub fn name(&self) -> &'a [u8] {
+        let start = self
+            .path
+            .iter()
+            .rposition(|c| *c == b'/')
+            .map_or(0, |i| i + 1);
+        &self.path[start..]
+    }
+    /// The inode of the entry.
+    pub fn inode(&self) -> &'a I {
+        self.inode
+    }
+    /// The nid of the entry.
+    pub fn nid(&self) -> Nid {
+        self.inode.nid()
+    }
+    /// The metadata of the entry.
+    pub fn info(&self) -> &'a InodeInfo {
+        self.inode.info()
+    }
+    /// The nid of the directory holding the entry, the starting directory is its own parent.
+    pub fn parent(&self) -> Nid {
+        self.parent
+    }
+    /// The number of components between the starting directory and the entry.
+    pub fn depth(&self) -> usize {
+        self.depth
+    }
+    /// Whether the same inode has already been visited under another path.
+    pub fn is_hardlink(&self) -> bool {
+        self.hardlink
+    }
+}
+
+#[derive(Default)]
+pub(crate) struct Pending {
+    pub(crate) path: Vec<u8>,
+    pub(crate) nid: Nid,
+    pub(crate) parent: Nid,
+    pub(crate) depth: usize,
+}
+
+/// Nids already visited.
+#[derive(Default)]
+pub(crate) struct NidSet(BTreeSet<Nid>);
+
+impl NidSet {
+    /// Insert `nid` and return whether it was already present.
+    pub(crate) fn insert(&mut self, nid: Nid) -> bool {
+        !self.0.insert(nid)
+    }
+}
+
+pub(crate) fn read_walk_inode<I>(filesystem: &dyn FileSystem<I>, nid: Nid) -> PosixResult<I>
+where
+    I: Inode,
+{
+    let info = filesystem.read_inode_info(nid)?;
+    let shared = filesystem.read_inode_xattrs_shared_entries(nid, &info)?;
+    Ok(I::new(filesystem.superblock(), info, nid, shared))
+}
+
+/// Record the visit of `inode` and return whether it is a hardlink to an inode visited before.
+/// Directories can't be hardlinked, so meeting one twice means the image is corrupted.
+pub(crate) fn check_visited<I>(visited: &mut NidSet, inode: &I) -> PosixResult<bool>
+where
+    I: Inode,
+{
+    let seen = visited.insert(inode.nid());
+    if seen && inode.info().inode_type() == Type::Directory {
+        return Err(EUCLEAN);
+    }
+    Ok(seen)
+}
+
+/// Append the children of the directory `pending` refers to.
+pub(crate) fn push_children<I>(
+    filesystem: &dyn FileSystem<I>,
+    inode: &I,
+    pending: &Pending,
+    queue: &mut Vec<Pending>,
+) -> PosixResult<()>
+where
+    I: Inode,
+{
+    for entry in filesystem.read_dir(inode)? {
+        let entry = entry?;
+        if entry.name() == b"." || entry.name() == b".." {
+            continue;
+        }
+        let mut path = Vec::new();
+        if pending.path != b"/" {
+            extend_from_slice(&mut path, &pending.path)?;
+        }
+        push_vec(&mut path, b'/')?;
+        extend_from_slice(&mut path, entry.name())?;
+        push_vec(
+            queue,
+            Pending {
+                path,
+                nid: entry.nid(),
+                parent: pending.nid,
+                depth: pending.depth + 1,
+            },
+        )?;
+    }
+    Ok(())
+}
+
+fn root_pending(start: Nid) -> PosixResult<Pending> {
+    let mut path = Vec::new();
+    push_vec(&mut path, b'/')?;
+    Ok(Pending {
+        path,
+        nid: start,
+        parent: start,
+        depth: 0,
+    })
+}
+
+/// Read the inode `pending` refers to, hand it to the visitor and return the inode if the walk
+/// should descend into it.
+fn visit<I>(
+    filesystem: &dyn FileSystem<I>,
+    pending: &Pending,
+    visited: &mut dyn FnMut(&I) -> PosixResult<bool>,
+    visitor: &mut dyn FnMut(&WalkEntry<'_, I>) -> PosixResult<WalkControl>,
+) -> PosixResult<(WalkControl, Option<I>)>
+where
+    I: Inode,
+{
+    let inode = read_walk_inode(filesystem, pending.nid)?;
+    let hardlink = visited(&inode)?;
+    let control = visitor(&WalkEntry {
+        path: &pending.path,
+        inode: &inode,
+        parent: pending.parent,
+        depth: pending.depth,
+        hardlink,
+    })?;
+    if control == WalkControl::Continue && inode.info().inode_type() == Type::Directory {
+        Ok((control, Some(inode)))
+    } else {
+        Ok((control, None))
+    }
+}
+
+pub(crate) fn walk<I>(
+    filesystem: &dyn FileSystem<I>,
+    start: Nid,
+    order: WalkOrder,
+    visitor: &mut dyn FnMut(&WalkEntry<'_, I>) -> PosixResult<WalkControl>,
+) -> PosixResult<()>
+where
+    I: Inode,
+{
+    let mut visited = NidSet::default();
+    let mut queue: Vec<Pending> = Vec::new();
+    push_vec(&mut queue, root_pending(start)?)?;
+    // Breadth first consumes the queue from the front, depth first from the back.
+    let mut head = 0;
+    while head < queue.len() {
+        let pending = match order {
+            WalkOrder::DepthFirst => queue.pop().ok_or(EUCLEAN)?,
+            WalkOrder::BreadthFirst => {
+                head += 1;
+                core::mem::take(&mut queue[head - 1])
+            }
+        };
+        let (control, dir) = visit(
+            filesystem,
+            &pending,
+            &mut |inode| check_visited(&mut visited, inode),
+            visitor,
+        )?;
+        if control == WalkControl::Stop {
+            break;
+        }
+        let Some(dir) = dir else {
+            continue;
+        };
+        if order == WalkOrder::BreadthFirst && head * 2 >= queue.len() {
+            queue.drain(..head);
+            head = 0;
+        }
+        let first = queue.len();
+        push_children(filesystem, &dir, &pending, &mut queue)?;
+        if order == WalkOrder::DepthFirst {
+            // Popped from the back, so reverse to keep the directory order.
+            queue[first..].reverse();
+        }
+    }
+    Ok(())
+}
+
+#[cfg(feature = "std")]
+mod parallel {
+    use super::*;
+    use core::mem;
+    use std::sync::{Condvar, Mutex, MutexGuard, PoisonError};
+    use std::thread;
+
+    #[derive(Default)]
+    struct State {
+        queue: Vec<Pending>,
+        visited: NidSet,
+        // Workers holding a directory whose children have not been queued yet.
+        busy: usize,
+        done: bool,
+        error: Option<Errno>,
+    }
+
+    struct Shared {
+        state: Mutex<State>,
+        wakeup: Condvar,
+    }
+
+    impl Shared {
+        // A panicking visitor must not take the other workers down with it before they are told
+        // to stop, so poisoning is ignored.
+        fn lock(&self) -> MutexGuard<'_, State> {
+            self.state.lock().unwrap_or_else(PoisonError::into_inner)
+        }
+
+        fn next(&self) -> Option<Pending> {
+            let mut state = self.lock();
+            loop {
+                if state.done {
+                    return None;
+                }
+                if let Some(pending) = state.queue.pop() {
+                    state.busy += 1;
+                    return Some(pending);
+                }
+                if state.busy == 0 {
+                    state.done = true;
+                    self.wakeup.notify_all();
+                    return None;
+                }
+                state = self
+                    .wakeup
+                    .wait(state)
+                    .unwrap_or_else(PoisonError::into_inner);
+            }
+        }
+
+        /// Hand the outcome of a directory back. No result means the worker panicked, in which
+        /// case the walk stops and the panic is propagated once every worker has returned.
+        fn finish(&self, children: Vec<Pending>, result: Option<PosixResult<WalkControl>>) {
+            let mut state = self.lock();
+            state.busy -= 1;
+            match result {
+                Some(Ok(WalkControl::Stop)) | None => state.done = true,
+                Some(Ok(_)) => state.queue.extend(children.into_iter().rev()),
+                Some(Err(e)) => {
+                    state.error.get_or_insert(e);
+                    state.done = true;
+                }
+            }
+            self.wakeup.notify_all();
+        }
+    }
+
+    /// Reports back to [`Shared::finish`] when dropped, so that even a panic while visiting
+    /// wakes up the other workers instead of leaving them waiting forever.
+    struct Task<'s> {
+        shared: &'s Shared,
+        children: Vec<Pending>,
+        result: Option<PosixResult<WalkControl>>,
+    }
+
+    impl Drop for Task<'_> {
+        fn drop(&mut self) {
+            self.shared
+                .finish(mem::take(&mut self.children), self.result.take());
+        }
+    }
+
+    fn work<I>(
+        filesystem: &(dyn FileSystem<I> + Sync),
+        shared: &Shared,
+        visitor: &(dyn Fn(&WalkEntry<'_, I>) -> PosixResult<WalkControl> + Sync),
+    ) where
+        I: Inode,
+    {
+        while let Some(pending) = shared.next() {
+            let mut task = Task {
+                shared,
+                children: Vec::new(),
+                result: None,
+            };
+            let result = visit(
+                filesystem,
+                &pending,
+                &mut |inode| check_visited(&mut shared.lock().visited, inode),
+                &mut |entry| visitor(entry),
+            )
+            .and_then(|(control, dir)| {
+                if let Some(dir) = dir {
+                    push_children(filesystem, &dir, &pending, &mut task.children)?;
+                }
+                Ok(control)
+            });
+            task.result = Some(result);
+        }
+    }
+
+    pub(crate) fn walk_parallel<I>(
+        filesystem: &(dyn FileSystem<I> + Sync),
+        start: Nid,
+        threads: usize,
+        visitor: &(dyn Fn(&WalkEntry<'_, I>) -> PosixResult<WalkControl> + Sync),
+    ) -> PosixResult<()>
+    where
+        I: Inode,
+    {
+        let threads = match threads {
+            0 => thread::available_parallelism().map_or(1, |n| n.get()),
+            n => n,
+        };
+        let mut state = State::default();
+        push_vec(&mut state.queue, root_pending(start)?)?;
+        let shared = Shared {
+            state: Mutex::new(state),
+            wakeup: Condvar::new(),
+        };
+        thread::scope(|scope| {
+            for _ in 0..threads {
+                scope.spawn(|| work(filesystem, &shared, visitor));
+            }
+        });
+        match shared
+            .state
+            .into_inner()
+            .unwrap_or_else(PoisonError::into_inner)
+            .error
+        {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+pub(crate) use parallel::walk_parallel;
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::super::data::backends::uncompressed::*;
+    use super::super::file::ImageFileSystem;
+    use super::super::inode::tests::*;
+    use super::super::operations::walk;
+    use super::super::superblock::tests::*;
+    use super::*;
+    use alloc::boxed::Box;
+    use std::collections::HashMap;
+    use std::vec;
+
+    fn collect(
+        filesystem: &dyn FileSystem<SimpleInode>,
+        order: WalkOrder,
+        control: &dyn Fn(&[u8]) -> WalkControl,
+    ) -> PosixResult<Vec<(Vec<u8>, usize, bool)>> {
+        let mut entries = Vec::new();
+        let root = filesystem.superblock().root_nid as Nid;
+        walk(filesystem, root, order, &mut |entry| {
+            entries.push((entry.path().to_vec(), entry.depth(), entry.is_hardlink()));
+            Ok(control(entry.path()))
+        })?;
+        Ok(entries)
+    }
+
+    fn paths(entries: &[(Vec<u8>, usize, bool)]) -> Vec<&[u8]> {
+        entries.iter().map(|(path, _, _)| &path[..]).collect()
+    }
+
+    #[test]
+    fn test_walk_fixtures() {
+        for testcase in load_fixtures_full().chain(load_fixtures_noxattr()) {
+            let sbi: SimpleBufferedFileSystem = SuperblockInfo::new(
+                Box::new(
+                    ImageFileSystem::try_new(UncompressedBackend::new(testcase.file)).unwrap(),
+                ),
+                HashMap::new(),
+                (),
+            );
+            let filesystem = &*sbi.filesystem;
+            let root = filesystem.superblock().root_nid as Nid;
+            let mut parents = HashMap::new();
+            walk(filesystem, root, WalkOrder::DepthFirst, &mut |entry| {
+                parents.insert(entry.path().to_vec(), (entry.nid(), entry.parent()));
+                Ok(WalkControl::Continue)
+            })
+            .unwrap();
+            assert_eq!(parents[&b"/"[..]], (root, root));
+            assert_eq!(
+                parents[&b"/texts/lipsum.txt"[..]].1,
+                parents[&b"/texts"[..]].0
+            );
+            assert_eq!(parents[&b"/blob.jpg"[..]].1, root);
+            assert!(parents.contains_key(&b"/images/inabukumori.jpg"[..]));
+
+            // Depth first keeps every subtree together, breadth first never goes back up.
+            let dfs = collect(filesystem, WalkOrder::DepthFirst, &|_| {
+                WalkControl::Continue
+            })
+            .unwrap();
+            let images = paths(&dfs).iter().position(|p| p == b"/images").unwrap();
+            assert_eq!(dfs[images + 1].0, b"/images/inabukumori.jpg");
+            let bfs = collect(filesystem, WalkOrder::BreadthFirst, &|_| {
+                WalkControl::Continue
+            })
+            .unwrap();
+            assert_eq!(bfs.len(), dfs.len());
+            assert!(bfs.windows(2).all(|w| w[0].1 <= w[1].1));
+            assert!(bfs.iter().all(|(_, _, hardlink)| !hardlink));
+
+            for order in [WalkOrder::DepthFirst, WalkOrder::BreadthFirst] {
+                let pruned = collect(filesystem, order, &|path| {
+                    if path == b"/images" {
+                        WalkControl::Prune
+                    } else {
+                        WalkControl::Continue
+                    }
+                })
+                .unwrap();
+                assert_eq!(pruned.len(), dfs.len() - 1);
+                assert!(paths(&pruned).contains(&&b"/images"[..]));
+                assert!(!paths(&pruned).contains(&&b"/images/inabukumori.jpg"[..]));
+
+                let stopped = collect(filesystem, order, &|path| {
+                    if path == b"/texts" {
+                        WalkControl::Stop
+                    } else {
+                        WalkControl::Continue
+                    }
+                })
+                .unwrap();
+                assert_eq!(paths(&stopped).last(), Some(&&b"/texts"[..]));
+            }
+
+            assert_eq!(
+                walk(filesystem, root, WalkOrder::DepthFirst, &mut |entry| {
+                    if entry.depth() > 0 {
+                        Err(EACCES)
+                    } else {
+                        Ok(WalkControl::Continue)
+                    }
+                }),
+                Err(EACCES)
+            );
+        }
+    }
+
+    #[test]
+    fn test_walk_hardlinks() {
+        let mut image = build_dir_image(&[(b".", 0, 2), (b"..", 0, 2), (b"a", 1, 7), (b"b", 1, 7)]);
+        add_symlink(&mut image, 1, b"a");
+        let fs =
+            ImageFileSystem::try_new(UncompressedBackend::new(CountingSource::new(image))).unwrap();
+        let filesystem: &dyn FileSystem<SimpleInode> = &fs;
+        let entries = collect(filesystem, WalkOrder::DepthFirst, &|_| {
+            WalkControl::Continue
+        })
+        .unwrap();
+        assert_eq!(
+            entries,
+            vec![
+                (b"/".to_vec(), 0, false),
+                (b"/a".to_vec(), 1, false),
+                (b"/b".to_vec(), 1, true)
+            ]
+        );
+
+        // A directory showing up twice can only be a loop in a corrupted image.
+        let image = build_dir_image(&[(b".", 0, 2), (b"..", 0, 2), (b"self", 0, 2)]);
+        let fs =
+            ImageFileSystem::try_new(UncompressedBackend::new(CountingSource::new(image))).unwrap();
+        let filesystem: &dyn FileSystem<SimpleInode> = &fs;
+        for order in [WalkOrder::DepthFirst, WalkOrder::BreadthFirst] {
+            assert_eq!(
+                collect(filesystem, order, &|_| WalkControl::Continue),
+                Err(EUCLEAN)
+            );
+        }
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn test_walk_parallel() {
+        use super::super::operations::walk_parallel;
+        use std::sync::Mutex;
+
+        for testcase in load_fixtures_full().chain(load_fixtures_noxattr()) {
+            let fs = ImageFileSystem::try_new(UncompressedBackend::new(testcase.file)).unwrap();
+            let filesystem: &dyn FileSystem<SimpleInode> = &fs;
+            let root = filesystem.superblock().root_nid as Nid;
+            let mut expected = collect(filesystem, WalkOrder::DepthFirst, &|_| {
+                WalkControl::Continue
+            })
+            .unwrap();
+            expected.sort();
+            for threads in [0, 1, 4] {
+                let entries = Mutex::new(Vec::new());
+                walk_parallel(&fs, root, threads, &|entry: &WalkEntry<'_, SimpleInode>| {
+                    entries.lock().unwrap().push((
+                        entry.path().to_vec(),
+                        entry.depth(),
+                        entry.is_hardlink(),
+                    ));
+                    Ok(WalkControl::Continue)
+                })
+                .unwrap();
+                let mut entries = entries.into_inner().unwrap();
+                entries.sort();
+                assert_eq!(entries, expected);
+            }
+            assert_eq!(
+                walk_parallel(&fs, root, 4, &|entry: &WalkEntry<'_, SimpleInode>| {
+                    if entry.name() == b"images" {
+                        Err(EACCES)
+                    } else {
+                        Ok(WalkControl::Continue)
+                    }
+                }),
+                Err(EACCES)
+            );
+            // A panicking visitor must unwind out of the walk rather than hang the other workers.
+            let panicked = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+                walk_parallel(&fs, root, 4, &|entry: &WalkEntry<'_, SimpleInode>| {
+                    assert_ne!(entry.name(), b"texts");
+                    Ok(WalkControl::Continue)
+                })
+            }));
+            assert!(panicked.is_err());
+        }
+    }
+}
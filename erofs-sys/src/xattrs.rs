@@ -2,14 +2,21 @@
 // SPDX-License-Identifier: MIT or GPL-2.0-or-later
 
 use super::alloc_helper::*;
+use super::cache::*;
 use super::data::raw_iters::*;
 use super::errnos::*;
+use super::inode::*;
+use super::superblock::*;
+use super::xxhash::*;
 use super::*;
 use crate::round;
 
 use alloc::vec::Vec;
 use core::mem::size_of;
 
+/// POSIX ACL Module
+pub mod acl;
+
 /// The header of the xattr entry index.
 /// This is used to describe the superblock's xattrs collection.
 #[derive(Clone, Copy)]
@@ -33,6 +40,7 @@ impl From<[u8; 12]> for XAttrSharedEntrySummary {
 pub(crate) const XATTR_ENTRY_SUMMARY_BUF: [u8; 12] = [0u8; 12];
 
 /// Represented as a inmemory memory entry index header used by SuperBlockInfo.
+#[derive(Clone)]
 pub struct XAttrSharedEntries {
     /// name filter for xattrs name
     pub name_filter: u32,
@@ -40,6 +48,17 @@ pub struct XAttrSharedEntries {
     pub shared_indexes: Vec<u32>,
 }
 
+impl XAttrSharedEntries {
+    /// Check the name bloom filter of the inode. Returning false means that the xattr is
+    /// definitely absent while true means that it may be present.
+    /// Callers must make sure that the filter is enabled in the superblock.
+    pub fn may_contain(&self, index: u32, name: &[u8]) -> bool {
+        let bit = xxh32(name, EROFS_XATTR_FILTER_SEED.wrapping_add(index))
+            & (EROFS_XATTR_FILTER_BITS - 1);
+        self.name_filter & (1 << bit) == 0
+    }
+}
+
 /// Represents the name index for infixes or prefixes.
 #[repr(C)]
 #[derive(Clone, Copy)]
@@ -100,17 +119,29 @@ impl From<[u8; 4]> for XAttrEntryHeader {
 
 /// Xattr Common Infix holds the prefix index in the first byte and all the common infix data in
 /// the rest of the bytes.
+#[derive(Debug)]
 pub struct XAttrInfix(pub(crate) Vec<u8>);
 
 impl XAttrInfix {
-    fn prefix_index(&self) -> u8 {
+    /// The index of the well-known prefix this long prefix extends, e.g. 1 for `user.`.
+    pub fn prefix_index(&self) -> u8 {
         self.0[0]
     }
-    fn name(&self) -> &[u8] {
+    /// The infix appended to the well-known prefix.
+    pub fn name(&self) -> &[u8] {
         &self.0[1..]
     }
+    /// The well-known prefix this long prefix extends, or None if the index is unknown.
+    pub fn prefix(&self) -> Option<&'static [u8]> {
+        EROFS_XATTRS_PREFIXS
+            .get(self.prefix_index() as usize)
+            .copied()
+    }
 }
 
+pub(crate) const EROFS_XATTR_FILTER_BITS: u32 = 32;
+pub(crate) const EROFS_XATTR_FILTER_SEED: u32 = 0x25BB_E08F;
+
 pub(crate) const EROFS_XATTR_LONG_PREFIX: u8 = 0x80;
 pub(crate) const EROFS_XATTR_LONG_MASK: u8 = EROFS_XATTR_LONG_PREFIX - 1;
 
@@ -134,6 +165,128 @@ pub enum XAttrValue {
     Vec(Vec<u8>),
 }
 
+/// Represents a fully decoded xattr entry whose name is resolved against the long prefixes.
+#[derive(Debug, Clone)]
+pub struct XAttrEntry {
+    pub(crate) index: u8,
+    pub(crate) name: Vec<u8>,
+    pub(crate) value: Vec<u8>,
+}
+
+impl XAttrEntry {
+    /// The base prefix index of the entry.
+    pub fn index(&self) -> u8 {
+        self.index
+    }
+    /// The base prefix of the entry, e.g. `user.`.
+    pub fn prefix(&self) -> &'static [u8] {
+        EROFS_XATTRS_PREFIXS[self.index as usize]
+    }
+    /// The full name of the entry including its prefix, e.g. `user.foo`.
+    pub fn full_name(&self) -> &[u8] {
+        &self.name
+    }
+    /// The name of the entry without the base prefix, e.g. `foo` for `user.foo`.
+    pub fn name(&self) -> &[u8] {
+        &self.name[self.prefix().len()..]
+    }
+    /// The value of the entry.
+    pub fn value(&self) -> &[u8] {
+        &self.value
+    }
+    /// Take the owned value out of the entry.
+    pub fn into_value(self) -> Vec<u8> {
+        self.value
+    }
+
+    pub(crate) fn matches(&self, index: u32, name: &[u8]) -> bool {
+        self.index as u32 == index && self.name() == name
+    }
+
+    pub(crate) fn try_clone(&self) -> PosixResult<Self> {
+        let mut name = Vec::new();
+        extend_from_slice(&mut name, &self.name)?;
+        let mut value = Vec::new();
+        extend_from_slice(&mut value, &self.value)?;
+        Ok(Self {
+            index: self.index,
+            name,
+            value,
+        })
+    }
+
+    pub(crate) fn copy_value(&self, buffer: &mut Option<&mut [u8]>) -> PosixResult<XAttrValue> {
+        match buffer.as_mut() {
+            Some(b) => {
+                if b.len() < self.value.len() {
+                    return Err(ERANGE);
+                }
+                b[..self.value.len()].copy_from_slice(&self.value);
+                Ok(XAttrValue::Buffer(self.value.len()))
+            }
+            None => {
+                let mut v = Vec::new();
+                extend_from_slice(&mut v, &self.value)?;
+                Ok(XAttrValue::Vec(v))
+            }
+        }
+    }
+
+    pub(crate) fn write_key(&self, buffer: Option<&mut [u8]>) -> PosixResult<usize> {
+        let len = self.name.len();
+        if let Some(buffer) = buffer {
+            if buffer.len() <= len {
+                return Err(ERANGE);
+            }
+            buffer[..len].copy_from_slice(&self.name);
+            buffer[len] = b'\0';
+        }
+        Ok(len + 1)
+    }
+}
+
+/// An optional per-filesystem cache of decoded shared xattr entries keyed by their index.
+/// Shared entries are typically a small set of labels or ACLs referenced by lots of inodes.
+/// The cache holds at most `capacity` entries and values larger than a block are never cached,
+/// which bounds its memory usage.
+pub struct XAttrSharedCache {
+    entries: TryLock<LruCache<u32, XAttrEntry>>,
+}
+
+impl XAttrSharedCache {
+    /// Create a cache holding at most `capacity` shared entries.
+    pub const fn new(capacity: usize) -> Self {
+        Self {
+            entries: TryLock::new(LruCache::new(capacity)),
+        }
+    }
+
+    /// Run `f` against the shared entry at `key`, decoding it with `load` if it is not cached.
+    /// The cache is bypassed if it is being used concurrently.
+    pub(crate) fn with_entry<R>(
+        &self,
+        key: u32,
+        max_value_len: usize,
+        load: impl FnOnce() -> PosixResult<XAttrEntry>,
+        f: impl FnOnce(&XAttrEntry) -> PosixResult<R>,
+    ) -> PosixResult<R> {
+        if let Some(mut entries) = self.entries.try_lock() {
+            if let Some(entry) = entries.get(key) {
+                return f(entry);
+            }
+        }
+        let entry = load()?;
+        let result = f(&entry);
+        if entry.value.len() <= max_value_len {
+            if let Some(mut entries) = self.entries.try_lock() {
+                // Failing to cache the entry is not an error for the caller.
+                let _ = entries.insert(key, entry);
+            }
+        }
+        result
+    }
+}
+
 /// An iterator to read xattrs by comparing the entry's name one by one and reads its value
 /// correspondingly.
 pub(crate) trait XAttrEntriesProvider {
@@ -142,7 +295,7 @@ pub(crate) trait XAttrEntriesProvider {
         &mut self,
         pfs: &[XAttrInfix],
         header: &XAttrEntryHeader,
-        buffer: &mut [u8],
+        buffer: Option<&mut [u8]>,
     ) -> PosixResult<usize>;
     fn query_xattr_value(
         &mut self,
@@ -153,6 +306,11 @@ pub(crate) trait XAttrEntriesProvider {
         buffer: &mut Option<&mut [u8]>,
     ) -> PosixResult<XAttrValue>;
     fn skip_xattr_value(&mut self, header: &XAttrEntryHeader) -> PosixResult<()>;
+    fn read_xattr_entry(
+        &mut self,
+        pfs: &[XAttrInfix],
+        header: &XAttrEntryHeader,
+    ) -> PosixResult<XAttrEntry>;
 }
 impl<'a> XAttrEntriesProvider for SkippableContinuousIter<'a> {
     fn get_entry_header(&mut self) -> PosixResult<XAttrEntryHeader> {
@@ -164,34 +322,43 @@ impl<'a> XAttrEntriesProvider for SkippableContinuousIter<'a> {
         &mut self,
         ifs: &[XAttrInfix],
         header: &XAttrEntryHeader,
-        buffer: &mut [u8],
+        buffer: Option<&mut [u8]>,
     ) -> PosixResult<usize> {
-        let mut cur = if header.name_index.is_long() {
+        let (prefix, infix) = if header.name_index.is_long() {
             let if_index: usize = header.name_index.into();
-            if let Some(infix) = ifs.get(if_index) {
-                let pf_index = infix.prefix_index();
-                let prefix = EROFS_XATTRS_PREFIXS[pf_index as usize];
-                let plen = prefix.len();
-
-                buffer[..plen].copy_from_slice(&prefix[..plen]);
-                buffer[plen..infix.name().len() + plen].copy_from_slice(infix.name());
-
-                plen + infix.name().len()
-            } else {
-                return Err(Errno::ENODATA);
+            match ifs.get(if_index) {
+                Some(infix) => (
+                    EROFS_XATTRS_PREFIXS.get(infix.prefix_index() as usize),
+                    infix.name(),
+                ),
+                None => (None, &[][..]),
             }
         } else {
             let pf_index: usize = header.name_index.into();
-            let prefix = EROFS_XATTRS_PREFIXS[pf_index];
-            let plen = prefix.len();
-            buffer[..plen].copy_from_slice(&prefix[..plen]);
-            plen
+            (EROFS_XATTRS_PREFIXS.get(pf_index), &[][..])
+        };
+
+        // Entries with unknown prefixes are not listed at all.
+        let Some(prefix) = prefix else {
+            self.skip(header.suffix_len as Off)?;
+            return Ok(0);
         };
 
-        self.read(&mut buffer[cur..cur + header.suffix_len as usize])?;
-        cur += header.suffix_len as usize;
-        buffer[cur] = b'\0';
-        Ok(cur + 1)
+        let plen = prefix.len() + infix.len();
+        let len = plen + header.suffix_len as usize;
+        match buffer {
+            None => self.skip(header.suffix_len as Off)?,
+            Some(buffer) => {
+                if buffer.len() <= len {
+                    return Err(ERANGE);
+                }
+                buffer[..prefix.len()].copy_from_slice(prefix);
+                buffer[prefix.len()..plen].copy_from_slice(infix);
+                self.read(&mut buffer[plen..len])?;
+                buffer[len] = b'\0';
+            }
+        }
+        Ok(len + 1)
     }
 
     fn query_xattr_value(
@@ -208,39 +375,37 @@ impl<'a> XAttrEntriesProvider for SkippableContinuousIter<'a> {
             size_of::<XAttrEntryHeader>() as Off
         );
 
-        let cur = if header.name_index.is_long() {
+        let matched = if header.name_index.is_long() {
             let if_index: usize = header.name_index.into();
-
-            if if_index >= ifs.len() {
-                return Err(ENODATA);
-            }
-
-            let infix = ifs.get(if_index).unwrap();
-            let ilen = infix.name().len();
-
-            let pf_index = infix.prefix_index();
-
-            if pf_index >= EROFS_XATTRS_PREFIXS.len() as u8 {
-                return Err(ENODATA);
-            }
-
-            if index != pf_index as u32
-                || name.len() != ilen + header.suffix_len as usize
-                || name[..ilen] != *infix.name()
-            {
-                return Err(ENODATA);
-            }
-            ilen
+            ifs.get(if_index).and_then(|infix| {
+                let ilen = infix.name().len();
+                let pf_index = infix.prefix_index();
+                if pf_index as usize >= EROFS_XATTRS_PREFIXS.len()
+                    || index != pf_index as u32
+                    || name.len() != ilen + header.suffix_len as usize
+                    || name[..ilen] != *infix.name()
+                {
+                    None
+                } else {
+                    Some(ilen)
+                }
+            })
         } else {
             let pf_index: usize = header.name_index.into();
-            if pf_index >= EROFS_XATTRS_PREFIXS.len() {
-                return Err(ENODATA);
+            if pf_index >= EROFS_XATTRS_PREFIXS.len()
+                || pf_index != index as usize
+                || header.suffix_len as usize != name.len()
+            {
+                None
+            } else {
+                Some(0)
             }
+        };
 
-            if pf_index != index as usize || header.suffix_len as usize != name.len() {
-                return Err(ENODATA);
-            }
-            0
+        // Skip the whole entry so that the next header can be read properly.
+        let Some(cur) = matched else {
+            self.skip(xattr_size)?;
+            return Err(ENODATA);
         };
 
         match self.try_cmp(&name[cur..]) {
@@ -276,4 +441,132 @@ impl<'a> XAttrEntriesProvider for SkippableContinuousIter<'a> {
             ) - header.suffix_len as Off,
         )
     }
+
+    fn read_xattr_entry(
+        &mut self,
+        ifs: &[XAttrInfix],
+        header: &XAttrEntryHeader,
+    ) -> PosixResult<XAttrEntry> {
+        let len = header.suffix_len as Off + header.value_len as Off;
+        let xattr_size = round!(UP, len, size_of::<XAttrEntryHeader>() as Off);
+        let (index, infix) = if header.name_index.is_long() {
+            let if_index: usize = header.name_index.into();
+            match ifs.get(if_index) {
+                Some(infix) => (infix.prefix_index(), infix.name()),
+                None => (u8::MAX, &[][..]),
+            }
+        } else {
+            (header.name_index.0, &[][..])
+        };
+        if index as usize >= EROFS_XATTRS_PREFIXS.len() {
+            self.skip(xattr_size)?;
+            return Err(ENODATA);
+        }
+
+        let mut name: Vec<u8> = Vec::new();
+        extend_from_slice(&mut name, EROFS_XATTRS_PREFIXS[index as usize])?;
+        extend_from_slice(&mut name, infix)?;
+        let mut suffix: Vec<u8> = vec_with_capacity(header.suffix_len as usize)?;
+        self.read(&mut suffix)?;
+        extend_from_slice(&mut name, &suffix)?;
+
+        let mut value: Vec<u8> = vec_with_capacity(header.value_len as usize)?;
+        self.read(&mut value)?;
+
+        self.skip(xattr_size - len)?;
+        Ok(XAttrEntry { index, name, value })
+    }
+}
+
+/// Create a provider over the inline xattr entries of the inode if there are any.
+pub(crate) fn inline_xattrs_provider<'a, I>(
+    fs: &'a dyn FileSystem<I>,
+    inode: &I,
+) -> PosixResult<Option<SkippableContinuousIter<'a>>>
+where
+    I: Inode,
+{
+    let sb = fs.superblock();
+    let shared_count = inode.xattrs_shared_entries().shared_indexes.len();
+    let inline_header_sz = size_of::<XAttrSharedEntrySummary>() as Off + shared_count as Off * 4;
+    if inline_header_sz > inode.info().xattr_size() {
+        return Ok(None);
+    }
+    SkippableContinuousIter::try_new(fs.continuous_iter(
+        sb.iloc(inode.nid()) + inode.info().inode_size() + inline_header_sz,
+        inode.info().xattr_size() - inline_header_sz,
+    )?)
+}
+
+/// An iterator over all the xattrs of an inode which yields each of them exactly once.
+/// Inline entries come first and shared entries follow, both in on-disk order.
+/// Entries whose prefix can't be resolved are skipped.
+pub struct XAttrIter<'a, I>
+where
+    I: Inode,
+{
+    fs: &'a dyn FileSystem<I>,
+    inline: Option<SkippableContinuousIter<'a>>,
+    shared: core::slice::Iter<'a, u32>,
+}
+
+impl<'a, I> XAttrIter<'a, I>
+where
+    I: Inode,
+{
+    pub(crate) fn try_new(fs: &'a dyn FileSystem<I>, inode: &'a I) -> PosixResult<Self> {
+        Ok(Self {
+            fs,
+            inline: inline_xattrs_provider(fs, inode)?,
+            shared: inode.xattrs_shared_entries().shared_indexes.iter(),
+        })
+    }
+
+    fn next_inline(&mut self) -> Option<PosixResult<XAttrEntry>> {
+        let provider = self.inline.as_mut()?;
+        while !provider.eof() {
+            let result = provider
+                .get_entry_header()
+                .and_then(|header| provider.read_xattr_entry(self.fs.xattr_infixes(), &header));
+            match result {
+                Err(ENODATA) => continue,
+                Err(e) => {
+                    // The stream is no longer aligned to an entry, so give up on inline entries.
+                    self.inline = None;
+                    return Some(Err(e));
+                }
+                Ok(entry) => return Some(Ok(entry)),
+            }
+        }
+        self.inline = None;
+        None
+    }
+
+    fn next_shared(&mut self) -> Option<PosixResult<XAttrEntry>> {
+        for index in self.shared.by_ref() {
+            let result = match self.fs.xattr_shared_cache() {
+                Some(cache) => cache.with_entry(
+                    *index,
+                    self.fs.superblock().blksz() as usize,
+                    || self.fs.read_xattr_shared_entry(*index),
+                    |entry| entry.try_clone(),
+                ),
+                None => self.fs.read_xattr_shared_entry(*index),
+            };
+            if !matches!(result, Err(ENODATA)) {
+                return Some(result);
+            }
+        }
+        None
+    }
+}
+
+impl<'a, I> Iterator for XAttrIter<'a, I>
+where
+    I: Inode,
+{
+    type Item = PosixResult<XAttrEntry>;
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_inline().or_else(|| self.next_shared())
+    }
 }
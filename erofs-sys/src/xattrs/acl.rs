@@ -0,0 +1,318 @@
+// Copyright 2024 Yiyang Wu
+// SPDX-License-Identifier: MIT or GPL-2.0-or-later
+
+use super::super::alloc_helper::*;
+use super::super::errnos::*;
+use super::super::*;
+use alloc::vec::Vec;
+use core::mem::size_of;
+
+/// Xattr name index of `system.posix_acl_access`.
+pub const EROFS_XATTR_INDEX_POSIX_ACL_ACCESS: u32 = 2;
+/// Xattr name index of `system.posix_acl_default`.
+pub const EROFS_XATTR_INDEX_POSIX_ACL_DEFAULT: u32 = 3;
+
+pub(crate) const POSIX_ACL_XATTR_VERSION: u32 = 0x0002;
+
+pub(crate) const ACL_USER_OBJ: u16 = 0x01;
+pub(crate) const ACL_USER: u16 = 0x02;
+pub(crate) const ACL_GROUP_OBJ: u16 = 0x04;
+pub(crate) const ACL_GROUP: u16 = 0x08;
+pub(crate) const ACL_MASK: u16 = 0x10;
+pub(crate) const ACL_OTHER: u16 = 0x20;
+
+/// Read permission bit of an ACL entry.
+pub const ACL_READ: u16 = 0x04;
+/// Write permission bit of an ACL entry.
+pub const ACL_WRITE: u16 = 0x02;
+/// Execute permission bit of an ACL entry.
+pub const ACL_EXECUTE: u16 = 0x01;
+
+/// Represents which of the two ACLs of an inode is queried.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AclType {
+    /// The access ACL which is stored in `system.posix_acl_access`.
+    Access,
+    /// The default ACL of a directory which is stored in `system.posix_acl_default`.
+    Default,
+}
+
+impl AclType {
+    /// The xattr name index the ACL is stored under. The name itself is always empty.
+    pub fn xattr_index(&self) -> u32 {
+        match self {
+            Self::Access => EROFS_XATTR_INDEX_POSIX_ACL_ACCESS,
+            Self::Default => EROFS_XATTR_INDEX_POSIX_ACL_DEFAULT,
+        }
+    }
+}
+
+/// The tag of an ACL entry along with the qualifier for named users and groups.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AclTag {
+    /// The owner of the file.
+    UserObj,
+    /// A named user.
+    User(u32),
+    /// The owning group of the file.
+    GroupObj,
+    /// A named group.
+    Group(u32),
+    /// The upper bound of the permissions granted to named users and all groups.
+    Mask,
+    /// Everyone else.
+    Other,
+}
+
+/// A single ACL entry.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AclEntry {
+    /// tag
+    pub tag: AclTag,
+    /// perm, a combination of ACL_READ, ACL_WRITE and ACL_EXECUTE.
+    pub perm: u16,
+}
+
+/// On-disk representation of an ACL entry inside the xattr value.
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub(crate) struct PosixAclXattrEntry {
+    pub(crate) e_tag: u16,
+    pub(crate) e_perm: u16,
+    pub(crate) e_id: u32,
+}
+
+impl From<[u8; 8]> for PosixAclXattrEntry {
+    fn from(value: [u8; 8]) -> Self {
+        Self {
+            e_tag: u16::from_le_bytes([value[0], value[1]]),
+            e_perm: u16::from_le_bytes([value[2], value[3]]),
+            e_id: u32::from_le_bytes([value[4], value[5], value[6], value[7]]),
+        }
+    }
+}
+
+/// A POSIX ACL decoded from the `system.posix_acl_access` or `system.posix_acl_default` xattrs.
+#[derive(Debug, Clone)]
+pub struct PosixAcl {
+    entries: Vec<AclEntry>,
+}
+
+impl TryFrom<&[u8]> for PosixAcl {
+    type Error = Errno;
+    fn try_from(value: &[u8]) -> Result<Self, Self::Error> {
+        const ENTRY_SIZE: usize = size_of::<PosixAclXattrEntry>();
+        if value.len() < 4 || (value.len() - 4) % ENTRY_SIZE != 0 {
+            return Err(EINVAL);
+        }
+        let version = u32::from_le_bytes([value[0], value[1], value[2], value[3]]);
+        if version != POSIX_ACL_XATTR_VERSION {
+            return Err(EOPNOTSUPP);
+        }
+
+        let mut entries = Vec::new();
+        for raw in value[4..].chunks_exact(ENTRY_SIZE) {
+            let raw = PosixAclXattrEntry::from(<[u8; ENTRY_SIZE]>::try_from(raw).unwrap());
+            let tag = match raw.e_tag {
+                ACL_USER_OBJ => AclTag::UserObj,
+                ACL_USER => AclTag::User(raw.e_id),
+                ACL_GROUP_OBJ => AclTag::GroupObj,
+                ACL_GROUP => AclTag::Group(raw.e_id),
+                ACL_MASK => AclTag::Mask,
+                ACL_OTHER => AclTag::Other,
+                _ => return Err(EINVAL),
+            };
+            push_vec(
+                &mut entries,
+                AclEntry {
+                    tag,
+                    perm: raw.e_perm & (ACL_READ | ACL_WRITE | ACL_EXECUTE),
+                },
+            )?;
+        }
+
+        let acl = Self { entries };
+        acl.validate()?;
+        Ok(acl)
+    }
+}
+
+impl PosixAcl {
+    /// Build the minimal ACL which is equivalent to the permission bits of the mode.
+    /// This is what the access check falls back to when an inode has no access ACL.
+    pub fn from_mode(mode: u16) -> PosixResult<Self> {
+        let mut entries = Vec::new();
+        for (tag, shift) in [
+            (AclTag::UserObj, 6),
+            (AclTag::GroupObj, 3),
+            (AclTag::Other, 0),
+        ] {
+            push_vec(
+                &mut entries,
+                AclEntry {
+                    tag,
+                    perm: (mode >> shift) & 0o7,
+                },
+            )?;
+        }
+        Ok(Self { entries })
+    }
+
+    /// The entries of the ACL in on-disk order.
+    pub fn entries(&self) -> &[AclEntry] {
+        &self.entries
+    }
+
+    /// The ACL_MASK entry if there is any.
+    pub fn mask(&self) -> Option<&AclEntry> {
+        self.entries.iter().find(|e| e.tag == AclTag::Mask)
+    }
+
+    fn validate(&self) -> PosixResult<()> {
+        let count = |tag| self.entries.iter().filter(|e| e.tag == tag).count();
+        let named = self
+            .entries
+            .iter()
+            .any(|e| matches!(e.tag, AclTag::User(_) | AclTag::Group(_)));
+        if count(AclTag::UserObj) != 1
+            || count(AclTag::GroupObj) != 1
+            || count(AclTag::Other) != 1
+            || count(AclTag::Mask) > 1
+            || (named && count(AclTag::Mask) == 0)
+        {
+            return Err(EINVAL);
+        }
+        Ok(())
+    }
+
+    /// Evaluate whether a process with `uid` and the groups `gids` is granted all of the `want`
+    /// permission bits on a file owned by `owner_uid` and `owner_gid`, following the POSIX.1e
+    /// access check algorithm. Returns EACCES if access is denied.
+    /// Note that capabilities such as those of the superuser are not taken into account.
+    pub fn check_access(
+        &self,
+        owner_uid: u32,
+        owner_gid: u32,
+        uid: u32,
+        gids: &[u32],
+        want: u16,
+    ) -> PosixResult<()> {
+        let want = want & (ACL_READ | ACL_WRITE | ACL_EXECUTE);
+        let granted = |perm: u16| {
+            if perm & want == want {
+                Ok(())
+            } else {
+                Err(EACCES)
+            }
+        };
+        let masked = |perm: u16| granted(self.mask().map_or(perm, |m| perm & m.perm));
+
+        let mut found = false;
+        for entry in self.entries.iter() {
+            match entry.tag {
+                AclTag::UserObj if owner_uid == uid => return granted(entry.perm),
+                AclTag::User(id) if id == uid => return masked(entry.perm),
+                AclTag::GroupObj if gids.contains(&owner_gid) => {
+                    found = true;
+                    if entry.perm & want == want {
+                        return masked(entry.perm);
+                    }
+                }
+                AclTag::Group(id) if gids.contains(&id) => {
+                    found = true;
+                    if entry.perm & want == want {
+                        return masked(entry.perm);
+                    }
+                }
+                AclTag::Other => {
+                    return if found {
+                        Err(EACCES)
+                    } else {
+                        granted(entry.perm)
+                    }
+                }
+                _ => {}
+            }
+        }
+        Err(EIO)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encode(entries: &[(u16, u16, u32)]) -> Vec<u8> {
+        let mut buf = Vec::new();
+        buf.extend_from_slice(&POSIX_ACL_XATTR_VERSION.to_le_bytes());
+        for (tag, perm, id) in entries {
+            buf.extend_from_slice(&tag.to_le_bytes());
+            buf.extend_from_slice(&perm.to_le_bytes());
+            buf.extend_from_slice(&id.to_le_bytes());
+        }
+        buf
+    }
+
+    #[test]
+    fn test_posix_acl_decode() {
+        let raw = encode(&[
+            (ACL_USER_OBJ, 6, u32::MAX),
+            (ACL_USER, 6, 1000),
+            (ACL_GROUP_OBJ, 4, u32::MAX),
+            (ACL_GROUP, 7, 2000),
+            (ACL_MASK, 6, u32::MAX),
+            (ACL_OTHER, 0, u32::MAX),
+        ]);
+        let acl = PosixAcl::try_from(&raw[..]).unwrap();
+        assert_eq!(acl.entries().len(), 6);
+        assert_eq!(
+            acl.entries()[1],
+            AclEntry {
+                tag: AclTag::User(1000),
+                perm: ACL_READ | ACL_WRITE
+            }
+        );
+
+        // owner
+        assert!(acl.check_access(0, 0, 0, &[0], ACL_WRITE).is_ok());
+        assert_eq!(acl.check_access(0, 0, 0, &[0], ACL_EXECUTE), Err(EACCES));
+        // named user
+        assert!(acl.check_access(0, 0, 1000, &[1000], ACL_READ).is_ok());
+        // named group is limited by the mask
+        assert!(acl.check_access(0, 0, 3000, &[2000], ACL_WRITE).is_ok());
+        assert_eq!(
+            acl.check_access(0, 0, 3000, &[2000], ACL_EXECUTE),
+            Err(EACCES)
+        );
+        // matching group without the permission denies instead of falling back to other
+        assert_eq!(acl.check_access(0, 0, 3000, &[0], ACL_WRITE), Err(EACCES));
+        // other
+        assert_eq!(acl.check_access(0, 0, 3000, &[3000], ACL_READ), Err(EACCES));
+    }
+
+    #[test]
+    fn test_posix_acl_invalid() {
+        assert_eq!(PosixAcl::try_from(&[2u8, 0, 0][..]).unwrap_err(), EINVAL);
+        let mut raw = encode(&[(ACL_USER_OBJ, 6, 0), (ACL_GROUP_OBJ, 4, 0)]);
+        assert_eq!(PosixAcl::try_from(&raw[..]).unwrap_err(), EINVAL);
+        raw[0] = 1;
+        assert_eq!(PosixAcl::try_from(&raw[..]).unwrap_err(), EOPNOTSUPP);
+        let raw = encode(&[
+            (ACL_USER_OBJ, 6, 0),
+            (ACL_USER, 6, 1000),
+            (ACL_GROUP_OBJ, 4, 0),
+            (ACL_OTHER, 0, 0),
+        ]);
+        assert_eq!(PosixAcl::try_from(&raw[..]).unwrap_err(), EINVAL);
+    }
+
+    #[test]
+    fn test_posix_acl_from_mode() {
+        let acl = PosixAcl::from_mode(0o750).unwrap();
+        assert!(acl.check_access(1, 2, 1, &[], ACL_WRITE).is_ok());
+        assert!(acl
+            .check_access(1, 2, 3, &[2], ACL_READ | ACL_EXECUTE)
+            .is_ok());
+        assert_eq!(acl.check_access(1, 2, 3, &[4], ACL_READ), Err(EACCES));
+    }
+}
@@ -0,0 +1,86 @@
+// Copyright 2024 Yiyang Wu
+// SPDX-License-Identifier: MIT or GPL-2.0-or-later
+
+// A no_std implementation of the 32-bit xxHash algorithm.
+// EROFS uses it to build the xattr name bloom filter, the same way as lib/xxhash.c in the kernel.
+const PRIME32_1: u32 = 0x9E37_79B1;
+const PRIME32_2: u32 = 0x85EB_CA77;
+const PRIME32_3: u32 = 0xC2B2_AE3D;
+const PRIME32_4: u32 = 0x27D4_EB2F;
+const PRIME32_5: u32 = 0x1656_67B1;
+
+fn read_u32(data: &[u8]) -> u32 {
+    u32::from_le_bytes([data[0], data[1], data[2], data[3]])
+}
+
+fn round(acc: u32, input: u32) -> u32 {
+    acc.wrapping_add(input.wrapping_mul(PRIME32_2))
+        .rotate_left(13)
+        .wrapping_mul(PRIME32_1)
+}
+
+/// Calculate the xxh32 digest of the input with the given seed.
+pub(crate) fn xxh32(input: &[u8], seed: u32) -> u32 {
+    let len = input.len();
+    let mut data = input;
+
+    let mut h32 = if len >= 16 {
+        let mut v1 = seed.wrapping_add(PRIME32_1).wrapping_add(PRIME32_2);
+        let mut v2 = seed.wrapping_add(PRIME32_2);
+        let mut v3 = seed;
+        let mut v4 = seed.wrapping_sub(PRIME32_1);
+        while data.len() >= 16 {
+            v1 = round(v1, read_u32(&data[0..4]));
+            v2 = round(v2, read_u32(&data[4..8]));
+            v3 = round(v3, read_u32(&data[8..12]));
+            v4 = round(v4, read_u32(&data[12..16]));
+            data = &data[16..];
+        }
+        v1.rotate_left(1)
+            .wrapping_add(v2.rotate_left(7))
+            .wrapping_add(v3.rotate_left(12))
+            .wrapping_add(v4.rotate_left(18))
+    } else {
+        seed.wrapping_add(PRIME32_5)
+    };
+
+    h32 = h32.wrapping_add(len as u32);
+
+    while data.len() >= 4 {
+        h32 = h32
+            .wrapping_add(read_u32(data).wrapping_mul(PRIME32_3))
+            .rotate_left(17)
+            .wrapping_mul(PRIME32_4);
+        data = &data[4..];
+    }
+
+    for byte in data {
+        h32 = h32
+            .wrapping_add((*byte as u32).wrapping_mul(PRIME32_5))
+            .rotate_left(11)
+            .wrapping_mul(PRIME32_1);
+    }
+
+    h32 ^= h32 >> 15;
+    h32 = h32.wrapping_mul(PRIME32_2);
+    h32 ^= h32 >> 13;
+    h32 = h32.wrapping_mul(PRIME32_3);
+    h32 ^= h32 >> 16;
+    h32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_xxh32_vectors() {
+        assert_eq!(xxh32(b"", 0), 0x02CC_5D05);
+        assert_eq!(xxh32(b"a", 0), 0x550D_7456);
+        assert_eq!(xxh32(b"abc", 0), 0x32D1_53FF);
+        assert_eq!(
+            xxh32(b"Nobody inspects the spammish repetition", 0),
+            0xE229_3B2F
+        );
+    }
+}
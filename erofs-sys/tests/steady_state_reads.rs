@@ -0,0 +1,157 @@
+// Copyright 2024 Yiyang Wu
+// SPDX-License-Identifier: MIT or GPL-2.0-or-later
+
+//! Checks that reads of file backed images don't allocate once warmed up. This lives in a test
+//! binary of its own since counting allocations takes over the global allocator.
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+
+use erofs_sys::data::backends::uncompressed::UncompressedBackend;
+use erofs_sys::data::Buffer;
+use erofs_sys::file::ImageFileSystem;
+use erofs_sys::inode::{Inode, InodeInfo};
+use erofs_sys::superblock::{FileSystem, SuperBlock};
+use erofs_sys::xattrs::XAttrSharedEntries;
+use erofs_sys::{Nid, Off};
+
+/// Counts the heap allocations of every thread separately, so that tests running in parallel
+/// don't disturb each other.
+struct CountingAllocator;
+
+thread_local! {
+    static ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+}
+
+fn count_allocation() {
+    let _ = ALLOCATIONS.try_with(|count| count.set(count.get() + 1));
+}
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        count_allocation();
+        // SAFETY: The caller's guarantees are passed on to the system allocator as is.
+        unsafe { System.alloc(layout) }
+    }
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        count_allocation();
+        // SAFETY: The caller's guarantees are passed on to the system allocator as is.
+        unsafe { System.alloc_zeroed(layout) }
+    }
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        count_allocation();
+        // SAFETY: The caller's guarantees are passed on to the system allocator as is.
+        unsafe { System.realloc(ptr, layout, new_size) }
+    }
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        // SAFETY: The caller's guarantees are passed on to the system allocator as is.
+        unsafe { System.dealloc(ptr, layout) }
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAllocator = CountingAllocator;
+
+/// The number of heap allocations made by the current thread so far.
+fn allocations() -> usize {
+    ALLOCATIONS.with(|count| count.get())
+}
+
+struct SimpleInode {
+    info: InodeInfo,
+    xattrs_shared_entries: XAttrSharedEntries,
+    nid: Nid,
+}
+
+impl Inode for SimpleInode {
+    fn new(
+        _sb: &SuperBlock,
+        info: InodeInfo,
+        nid: Nid,
+        xattrs_shared_entries: XAttrSharedEntries,
+    ) -> Self {
+        Self {
+            info,
+            xattrs_shared_entries,
+            nid,
+        }
+    }
+    fn info(&self) -> &InodeInfo {
+        &self.info
+    }
+    fn xattrs_shared_entries(&self) -> &XAttrSharedEntries {
+        &self.xattrs_shared_entries
+    }
+    fn nid(&self) -> Nid {
+        self.nid
+    }
+}
+
+fn iget(filesystem: &dyn FileSystem<SimpleInode>, nid: Nid) -> SimpleInode {
+    let info = filesystem.read_inode_info(nid).unwrap();
+    let shared = filesystem
+        .read_inode_xattrs_shared_entries(nid, &info)
+        .unwrap();
+    SimpleInode::new(filesystem.superblock(), info, nid, shared)
+}
+
+fn lookup(filesystem: &dyn FileSystem<SimpleInode>, path: &[&[u8]]) -> SimpleInode {
+    let mut inode = iget(filesystem, filesystem.superblock().root_nid as Nid);
+    for name in path {
+        let nid = filesystem.find_nid(&inode, name).unwrap().unwrap();
+        inode = iget(filesystem, nid);
+    }
+    inode
+}
+
+/// Drain the iterator, returning the number of bytes and buffers it yielded.
+fn drain<'a>(
+    iter: impl Iterator<Item = erofs_sys::PosixResult<impl Buffer + 'a>>,
+) -> (usize, usize) {
+    iter.fold((0, 0), |(len, count), buf| {
+        (len + buf.unwrap().content().len(), count + 1)
+    })
+}
+
+#[test]
+fn test_file_backed_steady_state_reads() {
+    for blksz in [512, 1024, 2048, 4096] {
+        let path = format!("{}/tests/sample_{blksz}.img", env!("CARGO_MANIFEST_DIR"));
+        let image = std::fs::read(path).unwrap();
+        let fs = ImageFileSystem::try_new(UncompressedBackend::new(image)).unwrap();
+        let filesystem: &dyn FileSystem<SimpleInode> = &fs;
+        let inode = lookup(filesystem, &[b"images", b"inabukumori.jpg"]);
+        let size = inode.info().file_size();
+        let mut buf = vec![0u8; size as usize];
+
+        // Warm up whatever is set up lazily, such as the pooled buffers.
+        let traverse = || {
+            let mapped = drain(filesystem.mapped_iter(&inode, 0).unwrap());
+            let ranged = drain(
+                filesystem
+                    .mapped_range_iter(&inode, 100, size - 200)
+                    .unwrap(),
+            );
+            let continuous = drain(filesystem.continuous_iter(0, 4 * blksz as Off).unwrap());
+            (mapped, ranged, continuous)
+        };
+        traverse();
+
+        // Reads don't allocate at all.
+        let before = allocations();
+        for offset in [0, 100, 5000] {
+            let len = buf.len() - offset;
+            assert_eq!(filesystem.read_at(&inode, offset as Off, &mut buf), Ok(len));
+        }
+        assert_eq!(allocations(), before);
+
+        // Traversals only allocate their iterator, not the buffers they yield.
+        let before = allocations();
+        let (mapped, ranged, continuous) = traverse();
+        assert_eq!(allocations(), before + 3);
+        assert_eq!(mapped.0 as Off, size);
+        assert!(mapped.1 > 1);
+        assert_eq!(ranged.0 as Off, size - 200);
+        assert_eq!(continuous, (4 * blksz, 4));
+    }
+}
@@ -1,42 +1,32 @@
 use clap::{arg, Parser};
 use erofs_sys::data::backends::uncompressed::UncompressedBackend;
-use erofs_sys::data::*;
+use erofs_sys::dir::FileType as DirentFileType;
 use erofs_sys::errnos::Errno::*;
 use erofs_sys::file::ImageFileSystem;
 use erofs_sys::inode::*;
 use erofs_sys::operations::*;
 use erofs_sys::superblock::FileSystem as ErofsFileSystem;
 use erofs_sys::superblock::SuperBlock;
+use erofs_sys::xattrs::acl::*;
 use erofs_sys::xattrs::*;
-use erofs_sys::{Nid, Off, PosixResult};
+use erofs_sys::{Nid, PosixResult};
 use fuser::Filesystem as FuseFileSystem;
 use fuser::MountOption;
 use fuser::{
-    FileAttr, FileType, ReplyAttr, ReplyData, ReplyDirectory, ReplyEntry, Request, FUSE_ROOT_ID,
+    FileAttr, FileType, ReplyAttr, ReplyData, ReplyDirectory, ReplyEmpty, ReplyEntry, ReplyXattr,
+    Request, FUSE_ROOT_ID,
 };
 use std::collections::{hash_map::Entry, HashMap};
 use std::ffi::OsStr;
 use std::ffi::*;
 use std::fs::File;
 use std::os::unix::ffi::OsStrExt;
-use std::os::unix::fs::FileExt;
 use std::path::Path;
 use std::time::Duration;
 use std::time::SystemTime;
 use std::time::UNIX_EPOCH;
 
 struct FuseCollection(HashMap<Nid, SimpleInode>);
-struct FuseFile(File);
-
-impl Source for FuseFile {
-    fn fill(&self, data: &mut [u8], _device_id: i32, offset: Off) -> PosixResult<u64> {
-        self.0
-            .read_at(data, offset)
-            .map_or(Err(ERANGE), |size| Ok(size as u64))
-    }
-}
-
-impl FileSource for FuseFile {}
 
 struct SimpleInode {
     info: InodeInfo,
@@ -93,8 +83,24 @@ fn system_time_from_time(secs: i64, nsecs: u32) -> SystemTime {
     }
 }
 
-fn file_type_from_type(ty: Type) -> FileType {
-    match ty {
+/// The primary gid of the caller followed by its supplementary groups. FUSE only passes the
+/// former, so the rest is taken from the `Groups:` line of `/proc/<pid>/status`. If the process
+/// is already gone only the primary gid is checked.
+fn caller_groups(pid: u32, gid: u32) -> Vec<u32> {
+    let mut gids = vec![gid];
+    let status = std::fs::read_to_string(format!("/proc/{pid}/status")).unwrap_or_default();
+    if let Some(groups) = status.lines().find_map(|l| l.strip_prefix("Groups:")) {
+        gids.extend(
+            groups
+                .split_whitespace()
+                .filter_map(|g| g.parse::<u32>().ok()),
+        );
+    }
+    gids
+}
+
+fn file_type_from_type(ty: Type) -> PosixResult<FileType> {
+    Ok(match ty {
         Type::Regular => FileType::RegularFile,
         Type::Directory => FileType::Directory,
         Type::Link => FileType::Symlink,
@@ -102,12 +108,16 @@ fn file_type_from_type(ty: Type) -> FileType {
         Type::Character => FileType::CharDevice,
         Type::Block => FileType::BlockDevice,
         Type::Socket => FileType::Socket,
-        Type::Unknown => panic!("Unknown Type"),
-    }
+        Type::Unknown => return Err(EUCLEAN),
+    })
 }
 
-fn get_file_attr_from_filesystem_inode(inode: &SimpleInode, sb: &SuperBlock) -> FileAttr {
-    match *inode.info() {
+fn get_file_attr_from_filesystem_inode(
+    inode: &SimpleInode,
+    sb: &SuperBlock,
+) -> PosixResult<FileAttr> {
+    let kind = file_type_from_type(inode.info().inode_type())?;
+    Ok(match *inode.info() {
         InodeInfo::Extended(e) => FileAttr {
             atime: system_time_from_time(e.i_mtime as i64, e.i_mtime_nsec),
             ino: inode.nid() + FUSE_ROOT_ID,
@@ -117,7 +127,7 @@ fn get_file_attr_from_filesystem_inode(inode: &SimpleInode, sb: &SuperBlock) ->
             ctime: system_time_from_time(e.i_mtime as i64, e.i_mtime_nsec),
             crtime: system_time_from_time(e.i_mtime as i64, e.i_mtime_nsec),
             perm: inode.info().inode_perm(),
-            kind: file_type_from_type(inode.info().inode_type()),
+            kind,
             nlink: e.i_nlink,
             blksize: 512,
             uid: e.i_uid,
@@ -134,7 +144,7 @@ fn get_file_attr_from_filesystem_inode(inode: &SimpleInode, sb: &SuperBlock) ->
             ctime: system_time_from_time(sb.build_time, sb.build_time_nsec as u32),
             crtime: system_time_from_time(sb.build_time, sb.build_time_nsec as u32),
             perm: inode.info().inode_perm(),
-            kind: file_type_from_type(inode.info().inode_type()),
+            kind,
             nlink: c.i_nlink as u32,
             blksize: 512,
             uid: c.i_uid as u32,
@@ -142,20 +152,28 @@ fn get_file_attr_from_filesystem_inode(inode: &SimpleInode, sb: &SuperBlock) ->
             rdev: 0,
             flags: 0,
         },
-    }
+    })
 }
 
-fn filetype_from_dtype(ty: u8) -> FileType {
-    match ty {
-        1 => FileType::RegularFile,
-        2 => FileType::Directory,
-        3 => FileType::CharDevice,
-        4 => FileType::BlockDevice,
-        5 => FileType::NamedPipe,
-        6 => FileType::Socket,
-        7 => FileType::Symlink,
-        _ => panic!("unknown"),
-    }
+fn filetype_from_dirent(
+    filesystem: &dyn ErofsFileSystem<SimpleInode>,
+    nid: Nid,
+    ty: DirentFileType,
+) -> PosixResult<FileType> {
+    Ok(match ty {
+        DirentFileType::Regular => FileType::RegularFile,
+        DirentFileType::Directory => FileType::Directory,
+        DirentFileType::CharDevice => FileType::CharDevice,
+        DirentFileType::BlockDevice => FileType::BlockDevice,
+        DirentFileType::Fifo => FileType::NamedPipe,
+        DirentFileType::Socket => FileType::Socket,
+        DirentFileType::Symlink => FileType::Symlink,
+        // FUSE has no way to report an unknown type, so ask the inode instead.
+        DirentFileType::Unknown => match filesystem.read_inode_info(nid)?.inode_type() {
+            Type::Unknown => return Err(EUCLEAN),
+            ty => file_type_from_type(ty)?,
+        },
+    })
 }
 
 fn nid_to_ino(sb: &SuperBlock, nid: Nid) -> u64 {
@@ -177,33 +195,62 @@ impl ErofsFuse {
         let inode = self
             .collection
             .iget(self.ino_to_nid(ino), self.filesystem.as_filesystem())?;
-        let mut symlink: Vec<u8> = Vec::new();
-        for res in self.filesystem.mapped_iter(inode, 0)? {
-            let block = res?;
-            let data = block.content();
-            symlink.extend_from_slice(data);
-        }
-        symlink.push(b'\0');
-        Ok(symlink)
+        readlink(self.filesystem.as_filesystem(), inode)
     }
-    fn try_read(&mut self, ino: u64, offset: i64, mut size: u32) -> PosixResult<Vec<u8>> {
+    fn try_list_xattrs(&mut self, ino: u64, buffer: &mut [u8]) -> PosixResult<usize> {
         let inode = self
             .collection
             .iget(self.ino_to_nid(ino), self.filesystem.as_filesystem())?;
-        let mut result: Vec<u8> = Vec::new();
-        for res in self.filesystem.mapped_iter(inode, offset as u64)? {
-            let block = res?;
-            let data = block.content();
-            let nsize = data.len().min(size as usize);
-            result.extend_from_slice(&data[..nsize]);
-            size -= nsize as u32;
-            if size == 0 {
-                break;
-            }
+        list_xattrs(self.filesystem.as_filesystem(), inode, buffer)
+    }
+    fn try_access(&mut self, ino: u64, uid: u32, gids: &[u32], mask: i32) -> PosixResult<()> {
+        let inode = self
+            .collection
+            .iget(self.ino_to_nid(ino), self.filesystem.as_filesystem())?;
+        if mask & W_OK != 0 {
+            return Err(EROFS);
         }
+        if mask == F_OK {
+            return Ok(());
+        }
+        // Like generic_permission, root may read anything but only execute files which have at
+        // least one execute bit set.
+        if uid == 0 {
+            let info = inode.info();
+            return if mask & X_OK == 0
+                || info.inode_type() == Type::Directory
+                || info.inode_perm() & 0o111 != 0
+            {
+                Ok(())
+            } else {
+                Err(EACCES)
+            };
+        }
+        let acl = match get_acl(self.filesystem.as_filesystem(), inode, AclType::Access)? {
+            Some(acl) => acl,
+            None => PosixAcl::from_mode(inode.info().inode_perm())?,
+        };
+        acl.check_access(
+            inode.info().uid(),
+            inode.info().gid(),
+            uid,
+            gids,
+            mask as u16,
+        )
+    }
+    fn try_read(&mut self, ino: u64, offset: i64, size: u32) -> PosixResult<Vec<u8>> {
+        let inode = self
+            .collection
+            .iget(self.ino_to_nid(ino), self.filesystem.as_filesystem())?;
+        let mut result = vec![0u8; size as usize];
+        let len = self.filesystem.read_at(inode, offset as u64, &mut result)?;
+        result.truncate(len);
         Ok(result)
     }
 }
+const F_OK: i32 = 0;
+const W_OK: i32 = 2;
+const X_OK: i32 = 1;
 const TTL: Duration = Duration::from_secs(1); // 1 second
 impl FuseFileSystem for ErofsFuse {
     fn init(&mut self, _req: &Request<'_>, _config: &mut fuser::KernelConfig) -> Result<(), c_int> {
@@ -224,22 +271,37 @@ impl FuseFileSystem for ErofsFuse {
         {
             Ok(inode) => {
                 let mut count = 1;
-                match self
-                    .filesystem
-                    .fill_dentries(inode, 0, offset as u64, &mut |dirent, _| {
-                        if reply.add(
-                            nid_to_ino(sb, dirent.desc().nid),
-                            count + 1,
-                            filetype_from_dtype(dirent.desc().file_type),
-                            OsStr::from_bytes(dirent.dirname()),
-                        ) {
-                            true
-                        } else {
-                            count += 1;
-                            false
+                let mut error = None;
+                let filesystem = self.filesystem.as_filesystem();
+                let result = filesystem.fill_dentries(inode, 0, offset as u64, &mut |dirent, _| {
+                    let nid = dirent.desc().nid;
+                    let kind = match dirent
+                        .file_type()
+                        .and_then(|ty| filetype_from_dirent(filesystem, nid, ty))
+                    {
+                        Ok(kind) => kind,
+                        Err(e) => {
+                            error = Some(e);
+                            return true;
                         }
-                    }) {
-                    Ok(()) => reply.ok(),
+                    };
+                    if reply.add(
+                        nid_to_ino(sb, nid),
+                        count + 1,
+                        kind,
+                        OsStr::from_bytes(dirent.dirname()),
+                    ) {
+                        true
+                    } else {
+                        count += 1;
+                        false
+                    }
+                });
+                match result {
+                    Ok(()) => match error {
+                        Some(e) => reply.error(e as i32),
+                        None => reply.ok(),
+                    },
                     Err(e) => reply.error(e as i32),
                 }
             }
@@ -250,7 +312,7 @@ impl FuseFileSystem for ErofsFuse {
         &mut self,
         _req: &Request<'_>,
         parent: u64,
-        _name: &std::ffi::OsStr,
+        name: &std::ffi::OsStr,
         reply: ReplyEntry,
     ) {
         let nid = self.ino_to_nid(parent);
@@ -258,15 +320,11 @@ impl FuseFileSystem for ErofsFuse {
             self.filesystem.as_filesystem(),
             &mut self.collection,
             nid,
-            _name.to_str().unwrap(),
-        ) {
-            Ok(inode) => {
-                reply.entry(
-                    &TTL,
-                    &get_file_attr_from_filesystem_inode(inode, self.filesystem.superblock()),
-                    0,
-                );
-            }
+            name.as_bytes(),
+        )
+        .and_then(|inode| get_file_attr_from_filesystem_inode(inode, self.filesystem.superblock()))
+        {
+            Ok(attr) => reply.entry(&TTL, &attr, 0),
             Err(e) => reply.error(e as i32),
         }
     }
@@ -274,11 +332,10 @@ impl FuseFileSystem for ErofsFuse {
         match self
             .collection
             .iget(self.ino_to_nid(ino), self.filesystem.as_filesystem())
-        {
-            Ok(inode) => reply.attr(
-                &TTL,
-                &get_file_attr_from_filesystem_inode(inode, self.filesystem.superblock()),
-            ),
+            .and_then(|inode| {
+                get_file_attr_from_filesystem_inode(inode, self.filesystem.superblock())
+            }) {
+            Ok(attr) => reply.attr(&TTL, &attr),
             Err(e) => reply.error(e as i32),
         }
     }
@@ -290,6 +347,23 @@ impl FuseFileSystem for ErofsFuse {
         }
     }
 
+    fn access(&mut self, req: &Request<'_>, ino: u64, mask: i32, reply: ReplyEmpty) {
+        let gids = caller_groups(req.pid(), req.gid());
+        match self.try_access(ino, req.uid(), &gids, mask) {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(e as i32),
+        }
+    }
+
+    fn listxattr(&mut self, _req: &Request<'_>, ino: u64, size: u32, reply: ReplyXattr) {
+        let mut buffer = vec![0u8; size as usize];
+        match self.try_list_xattrs(ino, &mut buffer) {
+            Ok(len) if size == 0 => reply.size(len as u32),
+            Ok(len) => reply.data(&buffer[..len]),
+            Err(e) => reply.error(e as i32),
+        }
+    }
+
     fn read(
         &mut self,
         _req: &Request,
@@ -365,8 +439,7 @@ fn main() {
         .write(true)
         .open(Path::new(&args.image))
         .unwrap();
-    let filesystem =
-        Box::new(ImageFileSystem::try_new(UncompressedBackend::new(FuseFile(file))).unwrap());
+    let filesystem = Box::new(ImageFileSystem::try_new(UncompressedBackend::new(file)).unwrap());
     let collection = FuseCollection(HashMap::new());
     let erofs_fuse = ErofsFuse {
         filesystem,